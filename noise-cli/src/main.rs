@@ -0,0 +1,495 @@
+// Key management and interop testing for noise-xx.
+//
+//   noise-xx keygen [--base64]
+//   noise-xx pubkey <secret> [--base64]
+//   noise-xx listen <addr|-> [--key <secret> | --store <store> --label <label>]
+//           [--remote <public>] [--prologue <text>] [--input <path>]
+//   noise-xx connect <addr|-> [--key <secret> | --store <store> --label <label>]
+//           [--remote <public>] [--prologue <text>] [--input <path>]
+//   noise-xx selftest
+//   noise-xx import <store> <label> <secret>
+//   noise-xx export <store> <label> [--base64]
+//   noise-xx rotate <store> <label>
+//   noise-xx keys <store>
+//
+// Keys are read as hex or base64, remote keys also as `liot1...` bech32 or
+// base64url. listen and connect run the handshake and then copy stdin to
+// the peer and the peer to stdout, like netcat. With `-` the Noise messages
+// go over stdin and stdout instead of TCP, e.g. behind socat on a serial
+// port, data to send is read from the terminal and received data is written
+// to stderr. --input reads the data to send from a file instead.
+// Messages are framed with a u16 big-endian length. The handshake is
+// aborted if the peer's static key is not --remote. connect checks it
+// before sending our static key, listen has already sent its key in
+// message 2 and only learns the peer's from message 3.
+//
+// Key stores are created by the first import. Their passphrase is read from
+// NOISE_KEYSTORE_PASSPHRASE or prompted for on the terminal.
+use noise_xx::{
+    Error, Handshake, KdfParams, KeyStore, NoiseRead, NoiseWrite, PublicKey, StaticKey, Transport,
+    KEYSTORE_SALT_LEN,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, process, thread};
+
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const SNOW_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+type Result<T> = std::result::Result<T, String>;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: noise-xx keygen [--base64]
+       noise-xx pubkey <secret> [--base64]
+       noise-xx listen <addr|-> [--key <secret> | --store <store> --label <label>]
+                [--remote <public>] [--prologue <text>] [--input <path>]
+       noise-xx connect <addr|-> [--key <secret> | --store <store> --label <label>]
+                [--remote <public>] [--prologue <text>] [--input <path>]
+       noise-xx selftest
+       noise-xx import <store> <label> <secret>
+       noise-xx export <store> <label> [--base64]
+       noise-xx rotate <store> <label>
+       noise-xx keys <store>"
+    );
+    process::exit(2)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn encode(key: &[u8; 32], base64: bool) -> String {
+    if base64 {
+        base64::encode(key)
+    } else {
+        hex(key)
+    }
+}
+
+// 64 hex digits or base64 of 32 bytes
+fn decode(s: &str) -> Result<[u8; 32]> {
+    let bytes = if s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..32)
+            .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    } else {
+        base64::decode(s).map_err(|e| format!("bad key {}: {}", s, e))?
+    };
+    bytes
+        .try_into()
+        .map_err(|_| format!("bad key {}: not 32 bytes", s))
+}
+
+fn random_key() -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).map_err(|e| format!("no randomness: {}", e))?;
+    Ok(key)
+}
+
+fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var("NOISE_KEYSTORE_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    // not stdin, which is the wire in `-` mode
+    let no_tty = |e: io::Error| format!("/dev/tty: {}, set NOISE_KEYSTORE_PASSPHRASE", e);
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(no_tty)?;
+    let stty = |arg| {
+        let tty = File::open("/dev/tty")?;
+        Command::new("stty")
+            .arg(arg)
+            .stdin(tty)
+            .stderr(Stdio::null())
+            .status()
+    };
+    stty("-echo").map_err(no_tty)?;
+    write!(tty, "key store passphrase: ").map_err(no_tty)?;
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    let _ = stty("echo");
+    let _ = writeln!(tty);
+    read.map_err(no_tty)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn load_store(path: &str, passphrase: &str) -> Result<KeyStore> {
+    KeyStore::load(path, passphrase.as_bytes()).map_err(|e| match e {
+        Error::Decrypt => format!("{}: wrong passphrase or corrupted", path),
+        e => format!("{}: {:?}", path, e),
+    })
+}
+
+fn save_store(store: &KeyStore, path: &str, passphrase: &str) -> Result<()> {
+    let mut salt = [0u8; KEYSTORE_SALT_LEN];
+    salt.copy_from_slice(&random_key()?[..KEYSTORE_SALT_LEN]);
+    store
+        .save(path, passphrase.as_bytes(), salt)
+        .map_err(|e| format!("{}: {:?}", path, e))
+}
+
+// import, export, rotate and keys
+fn run_store(command: &str, options: &Options) -> Result<()> {
+    let (path, label) = match (command, options.positional.as_slice()) {
+        ("keys", [path]) => (path, ""),
+        ("import", [path, label, _]) | ("export" | "rotate", [path, label]) => (path, &label[..]),
+        _ => usage(),
+    };
+    let passphrase = passphrase()?;
+    match command {
+        "import" => {
+            let secret = decode(&options.positional[2])?;
+            // a new store is created on first import
+            let mut store = match KeyStore::load(path, passphrase.as_bytes()) {
+                Err(Error::Io(io::ErrorKind::NotFound)) => KeyStore::new(KdfParams::default()),
+                _ => load_store(path, &passphrase)?,
+            };
+            store
+                .insert(label, secret, now())
+                .map_err(|_| format!("{}: bad or duplicate label {}", path, label))?;
+            save_store(&store, path, &passphrase)?;
+            println!("public {}", hex(&secret.public_key()));
+        }
+        "export" => {
+            let store = load_store(path, &passphrase)?;
+            let entry = store.get(label).ok_or(format!("no key {}", label))?;
+            println!("secret {}", encode(&entry.secret, options.base64));
+            println!("public {}", encode(&entry.public_key(), options.base64));
+        }
+        "rotate" => {
+            let mut store = load_store(path, &passphrase)?;
+            store
+                .rotate(label, random_key()?, now())
+                .map_err(|_| format!("no key {}", label))?;
+            save_store(&store, path, &passphrase)?;
+            let entry = store.get(label).unwrap();
+            println!("public {}", hex(&entry.public_key()));
+        }
+        _ => {
+            for entry in load_store(path, &passphrase)?.entries() {
+                println!(
+                    "{} {} {}",
+                    entry.label,
+                    entry.created,
+                    hex(&entry.public_key())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Options {
+    base64: bool,
+    key: Option<[u8; 32]>,
+    store: Option<String>,
+    label: Option<String>,
+    remote: Option<PublicKey>,
+    prologue: Vec<u8>,
+    input: Option<String>,
+    positional: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options> {
+    let mut options = Options {
+        base64: false,
+        key: None,
+        store: None,
+        label: None,
+        remote: None,
+        prologue: Vec::new(),
+        input: None,
+        positional: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--base64" => options.base64 = true,
+            "--key" => options.key = Some(decode(value()?)?),
+            "--store" => options.store = Some(value()?.clone()),
+            "--label" => options.label = Some(value()?.clone()),
+            "--remote" => {
+                let value = value()?;
+                let key = value.parse().or_else(|_| decode(value).map(PublicKey))?;
+                options.remote = Some(key)
+            }
+            "--prologue" => options.prologue = value()?.as_bytes().to_vec(),
+            "--input" => options.input = Some(value()?.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.positional.push(arg.clone()),
+        }
+    }
+    Ok(options)
+}
+
+fn read_frame(r: &mut impl Read, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut len = [0u8; 2];
+    match r.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        r => r?,
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    r.read_exact(&mut buf[..len])?;
+    Ok(Some(len))
+}
+
+fn write_frame(w: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    w.write_all(&(message.len() as u16).to_be_bytes())?;
+    w.write_all(message)?;
+    w.flush()
+}
+
+fn check_remote(remote: PublicKey, expected: Option<PublicKey>) -> Result<()> {
+    eprintln!("remote key {} ({})", remote, remote.fingerprint());
+    match expected {
+        Some(expected) if expected != remote => Err(format!("expected remote key {}", expected)),
+        _ => Ok(()),
+    }
+}
+
+fn handshake(
+    initiator: bool,
+    key: [u8; 32],
+    options: &Options,
+    r: &mut impl Read,
+    w: &mut impl Write,
+) -> Result<Transport> {
+    let mut hs = Handshake::new(initiator, random_key()?, key, &options.prologue);
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    while !hs.is_finished() {
+        if hs.is_my_turn() {
+            let len = hs
+                .write_message(&[], &mut message)
+                .map_err(|e| format!("handshake: {:?}", e))?;
+            write_frame(w, &message[..len]).map_err(|e| e.to_string())?;
+        } else {
+            let len = read_frame(r, &mut message)
+                .map_err(|e| e.to_string())?
+                .ok_or("peer closed during the handshake")?;
+            hs.read_message(&message[..len], &mut payload)
+                .map_err(|e| format!("handshake: {:?}", e))?;
+            // the initiator learns the key from msg2, before sending msg3
+            if let Some(remote) = hs.remote_key() {
+                check_remote(remote, options.remote)?;
+            }
+        }
+    }
+    hs.upgrade().map_err(|e| format!("handshake: {:?}", e))
+}
+
+fn send_loop(mut input: impl Read, mut w: impl Write, mut send: NoiseWrite) -> Result<()> {
+    let mut plain = vec![0u8; MAX_MESSAGE_LEN - TAG_LEN];
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        let n = input.read(&mut plain).map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Ok(());
+        }
+        let len = send
+            .write_message(&plain[..n], &mut message)
+            .map_err(|e| format!("encrypt: {:?}", e))?;
+        write_frame(&mut w, &message[..len]).map_err(|e| e.to_string())?;
+    }
+}
+
+fn receive_loop(mut r: impl Read, mut output: impl Write, mut recv: NoiseRead) -> Result<()> {
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let mut plain = vec![0u8; MAX_MESSAGE_LEN];
+    while let Some(len) = read_frame(&mut r, &mut message).map_err(|e| e.to_string())? {
+        let len = recv
+            .read_message(&message[..len], &mut plain)
+            .map_err(|e| format!("decrypt: {:?}", e))?;
+        output
+            .write_all(&plain[..len])
+            .and_then(|_| output.flush())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn run_session(initiator: bool, options: &Options) -> Result<()> {
+    let addr = match options.positional.as_slice() {
+        [addr] => addr,
+        _ => usage(),
+    };
+    let key = match (options.key, &options.store, &options.label) {
+        (Some(key), _, _) => key,
+        (None, Some(path), Some(label)) => {
+            let store = load_store(path, &passphrase()?)?;
+            store.get(label).ok_or(format!("no key {}", label))?.secret
+        }
+        (None, Some(_), None) => return Err("--store needs --label".into()),
+        _ => {
+            let key = random_key()?;
+            eprintln!("static key {}", PublicKey(key.public_key()));
+            key
+        }
+    };
+    let input = |default: &str| -> Result<Box<dyn Read + Send>> {
+        match options.input.as_deref().unwrap_or(default) {
+            "-" => Ok(Box::new(io::stdin())),
+            path => Ok(Box::new(
+                File::open(path).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+        }
+    };
+    if addr == "-" {
+        let (mut r, mut w) = (io::stdin(), io::stdout());
+        let transport = handshake(initiator, key, options, &mut r, &mut w)?;
+        let input = input("/dev/tty")?;
+        let (recv, send) = transport.split();
+        thread::spawn(move || {
+            if let Err(e) = send_loop(input, w, send) {
+                eprintln!("noise-xx: {}", e);
+            }
+        });
+        return receive_loop(r, io::stderr(), recv);
+    }
+
+    let stream = if initiator {
+        TcpStream::connect(addr)
+    } else {
+        TcpListener::bind(addr).and_then(|l| l.accept().map(|(s, _)| s))
+    }
+    .map_err(|e| format!("{}: {}", addr, e))?;
+    let mut r = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut w = stream.try_clone().map_err(|e| e.to_string())?;
+    let transport = handshake(initiator, key, options, &mut r, &mut w)?;
+    let input = input("-")?;
+    let (recv, send) = transport.split();
+    thread::spawn(move || {
+        if let Err(e) = send_loop(input, w, send) {
+            eprintln!("noise-xx: {}", e);
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+    });
+    receive_loop(r, io::stdout(), recv)
+}
+
+// Both roles against snow, with a transport message each way.
+fn selftest() -> Result<()> {
+    let builder = || snow::Builder::new(SNOW_PARAMS.parse().unwrap());
+    for initiator in [true, false] {
+        let key = random_key()?;
+        let snow_key = builder().generate_keypair().map_err(|e| e.to_string())?;
+        let mut ours = Handshake::new(initiator, random_key()?, key, &[]);
+        let mut snow = if initiator {
+            builder()
+                .local_private_key(&snow_key.private)
+                .build_responder()
+        } else {
+            builder()
+                .local_private_key(&snow_key.private)
+                .build_initiator()
+        }
+        .map_err(|e| e.to_string())?;
+
+        let mut message = [0u8; 200];
+        let mut payload = [0u8; 200];
+        while !ours.is_finished() {
+            if ours.is_my_turn() {
+                let len = ours
+                    .write_message(b"hello", &mut message)
+                    .map_err(|e| format!("noise-xx write: {:?}", e))?;
+                snow.read_message(&message[..len], &mut payload)
+                    .map_err(|e| format!("snow read: {}", e))?;
+            } else {
+                let len = snow
+                    .write_message(b"hello", &mut message)
+                    .map_err(|e| format!("snow write: {}", e))?;
+                ours.read_message(&message[..len], &mut payload)
+                    .map_err(|e| format!("noise-xx read: {:?}", e))?;
+            }
+        }
+        if snow.get_remote_static() != Some(&key.public_key()[..]) {
+            return Err("snow saw a different static key".into());
+        }
+        let mut ours = ours.upgrade().map_err(|e| format!("{:?}", e))?;
+        let mut snow = snow.into_transport_mode().map_err(|e| e.to_string())?;
+        if ours.remote_key().as_bytes()[..] != snow_key.public[..] {
+            return Err("noise-xx saw a different static key".into());
+        }
+
+        let len = ours.write_message(b"ping", &mut message).unwrap();
+        let n = snow
+            .read_message(&message[..len], &mut payload)
+            .map_err(|e| format!("snow decrypt: {}", e))?;
+        if &payload[..n] != b"ping" {
+            return Err("snow decrypted the wrong payload".into());
+        }
+        let len = snow.write_message(b"pong", &mut message).unwrap();
+        let n = ours
+            .read_message(&message[..len], &mut payload)
+            .map_err(|e| format!("noise-xx decrypt: {:?}", e))?;
+        if &payload[..n] != b"pong" {
+            return Err("noise-xx decrypted the wrong payload".into());
+        }
+        let role = if initiator { "initiator" } else { "responder" };
+        println!("{} against snow: ok", role);
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let command = args.first().map(String::as_str).unwrap_or_else(|| usage());
+    let options = parse_options(&args[1..])?;
+    match command {
+        "keygen" => {
+            let key = random_key()?;
+            println!("secret {}", encode(&key, options.base64));
+            println!("public {}", encode(&key.public_key(), options.base64));
+        }
+        "pubkey" => match options.positional.as_slice() {
+            [secret] => println!("{}", encode(&decode(secret)?.public_key(), options.base64)),
+            _ => usage(),
+        },
+        "listen" => run_session(false, &options)?,
+        "connect" => run_session(true, &options)?,
+        "selftest" => selftest()?,
+        "import" | "export" | "rotate" | "keys" => run_store(command, &options)?,
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("noise-xx: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn test_key_encodings() {
+        let key = [7u8; 32];
+        assert_eq!(decode(&encode(&key, false)).unwrap(), key);
+        assert_eq!(decode(&encode(&key, true)).unwrap(), key);
+        assert!(decode("0707").is_err());
+    }
+
+    #[test]
+    fn test_selftest() {
+        selftest().unwrap();
+    }
+}
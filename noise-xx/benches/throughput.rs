@@ -0,0 +1,74 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use noise_xx::{Handshake, Transport};
+
+const HEADER: [u8; 8] = [0xa5; 8];
+const BODY: [u8; 56] = [0x5a; 56];
+const BATCH: usize = 64;
+
+fn transport() -> Transport {
+    let mut buf_init = [0u8; 100];
+    let mut buf_resp = [0u8; 100];
+    let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+    let mut resp = Handshake::resp([2u8; 32], [3u8; 32], &[]);
+    let len = init.write_message(&[], &mut buf_init).unwrap();
+    resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+    let len = resp.write_message(&[], &mut buf_resp).unwrap();
+    init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+    let len = init.write_message(&[], &mut buf_init).unwrap();
+    resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+    init.upgrade().unwrap()
+}
+
+// BATCH small messages of header and body each, encrypted three ways
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    let mut out = [0u8; BATCH * 80];
+
+    group.bench_function("per_message", |b| {
+        b.iter_batched_ref(
+            transport,
+            |t| {
+                let mut offset = 0;
+                for _ in 0..BATCH {
+                    let mut payload = [0u8; 64];
+                    payload[..8].copy_from_slice(&HEADER);
+                    payload[8..].copy_from_slice(&BODY);
+                    offset += t.write_message(&payload, &mut out[offset..]).unwrap();
+                }
+                offset
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("vectored", |b| {
+        b.iter_batched_ref(
+            transport,
+            |t| {
+                let mut offset = 0;
+                for _ in 0..BATCH {
+                    offset += t
+                        .write_vectored(&[&HEADER, &BODY], &mut out[offset..])
+                        .unwrap();
+                }
+                offset
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let payloads = [&[0x5a; 64][..]; BATCH];
+    let mut lens = [0usize; BATCH];
+    group.bench_function("batch", |b| {
+        b.iter_batched_ref(
+            transport,
+            |t| t.write_batch(&payloads, &mut out, &mut lens).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+// Decrypts a capture with a key log written by a `keylog` build.
+//
+//   noise-keylog-decode [--padded] <keylog> <capture> [prologue]
+//
+// --padded strips the padding of sessions that used a `Padding` policy.
+// The capture is a pcap file of UDP datagrams or a dump of messages, each a
+// u32 LE length and the bytes.
+use noise_xx::keylog::{parse_dump, parse_pcap, Decoded, Decoder, KeyLog};
+use noise_xx::Padding;
+use std::{env, fs, process};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let padded = args.len() > 1 && args[1] == "--padded";
+    if padded {
+        args.remove(1);
+    }
+    if args.len() < 3 {
+        eprintln!(
+            "usage: {} [--padded] <keylog> <capture> [prologue]",
+            args[0]
+        );
+        process::exit(2);
+    }
+    let log = fs::read_to_string(&args[1]).unwrap_or_else(|e| {
+        eprintln!("{}: {}", args[1], e);
+        process::exit(1);
+    });
+    let capture = fs::read(&args[2]).unwrap_or_else(|e| {
+        eprintln!("{}: {}", args[2], e);
+        process::exit(1);
+    });
+    let prologue = args.get(3).map(|p| p.as_bytes()).unwrap_or(&[]);
+    let messages = match parse_pcap(&capture).or_else(|_| parse_dump(&capture)) {
+        Ok(messages) => messages,
+        Err(_) => {
+            eprintln!("{}: neither pcap nor a message dump", args[2]);
+            process::exit(1);
+        }
+    };
+
+    let mut decoder = Decoder::new(KeyLog::parse(&log), prologue);
+    if padded {
+        // any policy other than None, the trailer gives the length
+        decoder.set_padding(Padding::Block(1));
+    }
+    for (i, message) in messages.iter().enumerate() {
+        match decoder.decode(message) {
+            Decoded::Handshake {
+                session,
+                message,
+                payload,
+            } => println!(
+                "{} {} handshake {} {}",
+                i,
+                &hex(&session)[..16],
+                message,
+                hex(&payload)
+            ),
+            Decoded::Transport {
+                session,
+                from_initiator,
+                nonce,
+                payload,
+            } => println!(
+                "{} {} {} {} {}",
+                i,
+                &hex(&session)[..16],
+                if from_initiator { "i->r" } else { "r->i" },
+                nonce,
+                hex(&payload)
+            ),
+            Decoded::Unknown => println!("{} unknown {} bytes", i, message.len()),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey, Signature};
+use minicbor::{Decoder, Encoder};
+
+use crate::{Error, Handshake, StaticKey};
+
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+pub const MAX_CERT_LEN: usize = 1 + 2 * 34 + 2 + MAX_DEVICE_ID_LEN + 2 + 9 + 66;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Device,
+    Gateway,
+    Service,
+}
+
+impl Role {
+    fn from_u8(role: u8) -> Result<Self, Error> {
+        match role {
+            0 => Ok(Self::Device),
+            1 => Ok(Self::Gateway),
+            2 => Ok(Self::Service),
+            _ => Err(Error::Certificate),
+        }
+    }
+}
+
+// Binds a Noise static key to a device identity, signed by a fleet CA.
+//
+// CBOR: [key, issuer, device_id, role, not_after, signature]
+// where the signature covers the CBOR array of the first five fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate<'a> {
+    pub key: [u8; 32],
+    pub issuer: [u8; 32],
+    pub device_id: &'a str,
+    pub role: Role,
+    pub not_after: u64,
+    pub signature: [u8; 64],
+}
+
+impl<'a> Certificate<'a> {
+    pub fn sign(
+        key: [u8; 32],
+        device_id: &'a str,
+        role: Role,
+        not_after: u64,
+        ca_secret: &[u8; 32],
+    ) -> Result<Self, Error> {
+        let secret = SecretKey::from_bytes(ca_secret).map_err(|_| Error::Input)?;
+        let issuer = PublicKey::from(&secret);
+        let mut cert = Self {
+            key,
+            issuer: issuer.to_bytes(),
+            device_id,
+            role,
+            not_after,
+            signature: [0; 64],
+        };
+        let mut tbs = [0u8; MAX_CERT_LEN];
+        let len = cert.encode_fields(&mut tbs, false)?;
+        cert.signature = ExpandedSecretKey::from(&secret)
+            .sign(&tbs[..len], &issuer)
+            .to_bytes();
+        Ok(cert)
+    }
+    fn encode_fields(&self, buf: &mut [u8], signature: bool) -> Result<usize, Error> {
+        if self.device_id.len() > MAX_DEVICE_ID_LEN {
+            return Err(Error::Input);
+        }
+        let total = buf.len();
+        let mut writer = &mut buf[..];
+        let mut e = Encoder::new(&mut writer);
+        e.array(if signature { 6 } else { 5 })
+            .and_then(|e| e.bytes(&self.key))
+            .and_then(|e| e.bytes(&self.issuer))
+            .and_then(|e| e.str(self.device_id))
+            .and_then(|e| e.u8(self.role as u8))
+            .and_then(|e| e.u64(self.not_after))
+            .map_err(|_| Error::Input)?;
+        if signature {
+            e.bytes(&self.signature).map_err(|_| Error::Input)?;
+        }
+        Ok(total - writer.len())
+    }
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.encode_fields(buf, true)
+    }
+    // Returns the certificate and the number of bytes it occupied in `buf`.
+    pub fn decode(buf: &'a [u8]) -> Result<(Self, usize), Error> {
+        let mut d = Decoder::new(buf);
+        let cert = Self::decode_fields(&mut d)?;
+        if cert.device_id.len() > MAX_DEVICE_ID_LEN {
+            return Err(Error::Certificate);
+        }
+        Ok((cert, d.position()))
+    }
+    fn decode_fields(d: &mut Decoder<'a>) -> Result<Self, Error> {
+        if d.array().map_err(|_| Error::Certificate)? != Some(6) {
+            return Err(Error::Certificate);
+        }
+        let mut cert = Self {
+            key: [0; 32],
+            issuer: [0; 32],
+            device_id: "",
+            role: Role::Device,
+            not_after: 0,
+            signature: [0; 64],
+        };
+        let fixed = |d: &mut Decoder<'a>, out: &mut [u8]| {
+            let b = d.bytes().map_err(|_| Error::Certificate)?;
+            if b.len() != out.len() {
+                return Err(Error::Certificate);
+            }
+            out.copy_from_slice(b);
+            Ok(())
+        };
+        fixed(d, &mut cert.key)?;
+        fixed(d, &mut cert.issuer)?;
+        cert.device_id = d.str().map_err(|_| Error::Certificate)?;
+        cert.role = Role::from_u8(d.u8().map_err(|_| Error::Certificate)?)?;
+        cert.not_after = d.u64().map_err(|_| Error::Certificate)?;
+        fixed(d, &mut cert.signature)?;
+        Ok(cert)
+    }
+    // Checks the signature against the trust anchors (CA public keys), that
+    // the certificate is for `rs` and that it has not expired at `now`.
+    pub fn verify(&self, anchors: &[[u8; 32]], rs: &[u8; 32], now: u64) -> Result<(), Error> {
+        if !anchors.contains(&self.issuer) {
+            return Err(Error::Certificate);
+        }
+        if self.key != *rs {
+            return Err(Error::Certificate);
+        }
+        if now > self.not_after {
+            return Err(Error::Expired);
+        }
+        let issuer = PublicKey::from_bytes(&self.issuer).map_err(|_| Error::Certificate)?;
+        let mut tbs = [0u8; MAX_CERT_LEN];
+        let len = self.encode_fields(&mut tbs, false)?;
+        issuer
+            .verify_strict(&tbs[..len], &Signature::from(self.signature))
+            .map_err(|_| Error::Certificate)
+    }
+}
+
+impl<K: StaticKey> Handshake<K> {
+    // Reads message 2 (initiator) or 3 (responder) whose payload starts with
+    // the peer's certificate. Returns the verified certificate and the rest of
+    // the payload. A rejected certificate poisons the handshake, so nothing
+    // more is sent to the peer.
+    pub fn read_certified_message<'p>(
+        &mut self,
+        message: &[u8],
+        payload: &'p mut [u8],
+        anchors: &[[u8; 32]],
+        now: u64,
+    ) -> Result<(Certificate<'p>, &'p [u8]), Error> {
+        let len = self.read_message(message, payload)?;
+        let rs = self.remote_key().ok_or(Error::NotMyTurn)?;
+        let payload = &payload[..len];
+        let verified = Certificate::decode(payload).and_then(|(cert, cert_len)| {
+            cert.verify(anchors, rs.as_bytes(), now)
+                .map(|_| (cert, cert_len))
+        });
+        match verified {
+            Ok((cert, cert_len)) => Ok((cert, &payload[cert_len..])),
+            Err(e) => {
+                self.poison();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    const CA: [u8; 32] = [9u8; 32];
+
+    fn ca_pub() -> [u8; 32] {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&CA).unwrap();
+        ed25519_dalek::PublicKey::from(&secret).to_bytes()
+    }
+
+    fn pub_key(k: [u8; 32]) -> [u8; 32] {
+        x25519_dalek::x25519(k, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+
+    #[test]
+    fn test_certificate_roundtrip() {
+        let cert =
+            Certificate::sign(pub_key([1u8; 32]), "sensor-42", Role::Device, 100, &CA).unwrap();
+        let mut buf = [0u8; MAX_CERT_LEN];
+        let len = cert.encode(&mut buf).unwrap();
+        let (decoded, used) = Certificate::decode(&buf[..len]).unwrap();
+        assert_eq!(used, len);
+        assert_eq!(decoded, cert);
+        decoded
+            .verify(&[ca_pub()], &pub_key([1u8; 32]), 50)
+            .unwrap();
+
+        assert!(matches!(
+            decoded.verify(&[ca_pub()], &pub_key([1u8; 32]), 101),
+            Err(Error::Expired)
+        ));
+        assert!(matches!(
+            decoded.verify(&[[0u8; 32]], &pub_key([1u8; 32]), 50),
+            Err(Error::Certificate)
+        ));
+        assert!(matches!(
+            decoded.verify(&[ca_pub()], &pub_key([2u8; 32]), 50),
+            Err(Error::Certificate)
+        ));
+
+        let mut forged = decoded.clone();
+        forged.role = Role::Gateway;
+        assert!(matches!(
+            forged.verify(&[ca_pub()], &pub_key([1u8; 32]), 50),
+            Err(Error::Certificate)
+        ));
+    }
+
+    #[test]
+    fn test_certified_handshake() {
+        let mut buf_init = [0u8; 400];
+        let mut buf_resp = [0u8; 400];
+        let mut payload = [0u8; 400];
+        let (mut init, mut resp) = handshake_pair();
+        exchange(&mut init, &mut resp);
+
+        let cert = Certificate::sign(pub_key([3u8; 32]), "gw-1", Role::Gateway, 100, &CA).unwrap();
+        let cert_len = cert.encode(&mut payload).unwrap();
+        payload[cert_len..cert_len + 2].copy_from_slice(b"hi");
+        let len = resp
+            .write_message(&payload[..cert_len + 2], &mut buf_resp)
+            .unwrap();
+        let (remote, rest) = init
+            .read_certified_message(&buf_resp[..len], &mut buf_init, &[ca_pub()], 10)
+            .unwrap();
+        assert_eq!(remote.device_id, "gw-1");
+        assert_eq!(rest, b"hi");
+
+        let mut buf_init = [0u8; 400];
+        let cert = Certificate::sign(pub_key([5u8; 32]), "dev-1", Role::Device, 100, &CA).unwrap();
+        let cert_len = cert.encode(&mut payload).unwrap();
+        let len = init
+            .write_message(&payload[..cert_len], &mut buf_init)
+            .unwrap();
+        assert!(matches!(
+            resp.read_certified_message(&buf_init[..len], &mut buf_resp, &[ca_pub()], 10),
+            Err(Error::Certificate)
+        ));
+        assert!(matches!(resp.upgrade(), Err(Error::Poisoned)));
+    }
+
+    #[test]
+    fn test_rejected_certificate_poisons_handshake() {
+        let mut buf_init = [0u8; 400];
+        let mut buf_resp = [0u8; 400];
+        let mut payload = [0u8; 400];
+        let (mut init, mut resp) = handshake_pair();
+        exchange(&mut init, &mut resp);
+        // expired
+        let cert = Certificate::sign(pub_key([3u8; 32]), "gw-1", Role::Gateway, 5, &CA).unwrap();
+        let cert_len = cert.encode(&mut payload).unwrap();
+        let len = resp
+            .write_message(&payload[..cert_len], &mut buf_resp)
+            .unwrap();
+        assert!(matches!(
+            init.read_certified_message(&buf_resp[..len], &mut buf_init, &[ca_pub()], 10),
+            Err(Error::Expired)
+        ));
+        // msg3 would reveal our static key to the rejected peer
+        assert!(matches!(
+            init.write_message(&[], &mut buf_init),
+            Err(Error::Poisoned)
+        ));
+        assert!(matches!(
+            init.read_message(&buf_resp[..len], &mut payload),
+            Err(Error::Poisoned)
+        ));
+        assert!(!init.is_my_turn());
+        assert!(matches!(init.upgrade(), Err(Error::Poisoned)));
+    }
+}
@@ -15,6 +15,18 @@ impl CipherState {
             n: 0,
         }
     }
+    // REKEY(k) = ENCRYPT(k, maxnonce, zerolen, zeros), keeping the nonce
+    pub(crate) fn rekey(&mut self) {
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes[4..].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut k = [0u8; 32];
+        self.c
+            .encrypt_in_place_detached(&nonce_bytes.into(), &[], &mut k)
+            .unwrap();
+        let n = self.n;
+        *self = Self::new(k);
+        self.n = n;
+    }
     pub(crate) fn set_nonce(&mut self, nonce: u64) {
         self.n = nonce
     }
@@ -24,25 +36,63 @@ impl CipherState {
         plaintext: &[u8],
         ciphertext: &mut [u8],
     ) -> Result<usize, crate::Error> {
-        let len = plaintext.len() + TAG_LEN;
-        if ciphertext.len() < len {
+        self.encrypt_parts_with_ad(ad, &[plaintext], ciphertext)
+    }
+    // Encrypts the concatenation of `parts` as one message.
+    pub(crate) fn encrypt_parts_with_ad(
+        &mut self,
+        ad: &[u8],
+        parts: &[&[u8]],
+        ciphertext: &mut [u8],
+    ) -> Result<usize, crate::Error> {
+        let plaintext_len: usize = parts.iter().map(|p| p.len()).sum();
+        if ciphertext.len() < plaintext_len + TAG_LEN {
             return Err(crate::Error::Input);
         }
 
-        let (ciphertext, rest) = ciphertext.split_at_mut(plaintext.len());
-        let (ciphertext_mac, _) = rest.split_at_mut(TAG_LEN);
+        let mut offset = 0;
+        for part in parts {
+            ciphertext[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        self.encrypt_in_place_with_ad(ad, ciphertext, plaintext_len)
+    }
+    // Encrypts buf[..plaintext_len] and appends the tag.
+    pub(crate) fn encrypt_in_place_with_ad(
+        &mut self,
+        ad: &[u8],
+        buf: &mut [u8],
+        plaintext_len: usize,
+    ) -> Result<usize, crate::Error> {
+        let len = self.encrypt_in_place_at(self.n, ad, buf, plaintext_len)?;
+        self.n += 1;
+        Ok(len)
+    }
+    // Same as `encrypt_in_place_with_ad` with an explicit nonce, leaving n
+    // alone. The caller must never use a nonce twice.
+    pub(crate) fn encrypt_in_place_at(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buf: &mut [u8],
+        plaintext_len: usize,
+    ) -> Result<usize, crate::Error> {
+        let len = plaintext_len + TAG_LEN;
+        if buf.len() < len {
+            return Err(crate::Error::Input);
+        }
 
-        ciphertext.copy_from_slice(plaintext);
+        let (ciphertext, rest) = buf.split_at_mut(plaintext_len);
+        let (ciphertext_mac, _) = rest.split_at_mut(TAG_LEN);
 
         let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..].copy_from_slice(&self.n.to_le_bytes());
+        nonce_bytes[4..].copy_from_slice(&n.to_le_bytes());
 
         let tag = self
             .c
             .encrypt_in_place_detached(&nonce_bytes.into(), ad, ciphertext)
             .unwrap();
 
-        self.n += 1;
         ciphertext_mac.copy_from_slice(&tag);
         Ok(len)
     }
@@ -0,0 +1,416 @@
+use crate::{cipher_state::TAG_LEN, Error, Handshake, PublicKey, RecordType, Transport};
+
+// Fixed capacity FIFO of up to `Q` byte strings of at most `N` bytes each.
+pub(crate) struct Queue<const N: usize, const Q: usize> {
+    items: [(u8, usize, [u8; N]); Q],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize, const Q: usize> Queue<N, Q> {
+    pub(crate) fn new() -> Self {
+        Self {
+            items: [(0, 0, [0; N]); Q],
+            head: 0,
+            len: 0,
+        }
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub(crate) fn free(&self) -> usize {
+        Q - self.len
+    }
+    // Lets the caller fill the next slot, committing it if `f` succeeds.
+    pub(crate) fn push_with(
+        &mut self,
+        tag: u8,
+        f: impl FnOnce(&mut [u8; N]) -> Result<usize, Error>,
+    ) -> Result<(), Error> {
+        if self.len == Q {
+            return Err(Error::QueueFull);
+        }
+        let slot = &mut self.items[(self.head + self.len) % Q];
+        slot.1 = f(&mut slot.2)?;
+        slot.0 = tag;
+        self.len += 1;
+        Ok(())
+    }
+    pub(crate) fn retag_last(&mut self, tag: u8) {
+        if self.len > 0 {
+            self.items[(self.head + self.len - 1) % Q].0 = tag;
+        }
+    }
+    pub(crate) fn push(&mut self, tag: u8, data: &[u8]) -> Result<(), Error> {
+        self.push_with(tag, |slot| {
+            slot.get_mut(..data.len())
+                .ok_or(Error::Input)?
+                .copy_from_slice(data);
+            Ok(data.len())
+        })
+    }
+    pub(crate) fn peek(&self) -> Option<(u8, &[u8])> {
+        if self.len == 0 {
+            return None;
+        }
+        let (tag, len, data) = &self.items[self.head];
+        Some((*tag, &data[..*len]))
+    }
+    pub(crate) fn pop(&mut self) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % Q;
+            self.len -= 1;
+        }
+    }
+}
+
+const EVENT_COMPLETE: u8 = 0;
+const EVENT_DATA: u8 = 1;
+const EVENT_CLOSED: u8 = 2;
+const EVENT_HANDSHAKE_DATA: u8 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    // a non-empty handshake payload, copied like `Data`. The peer is only
+    // authenticated once the handshake is complete.
+    HandshakeData(usize),
+    HandshakeComplete { remote_key: PublicKey },
+    // application data copied into the buffer given to `poll_event`
+    Data(usize),
+    // closed by us, or by the peer with an authenticated close record
+    Closed,
+}
+
+enum State {
+    Handshake(Handshake),
+    Established(Transport),
+    Closed,
+}
+
+// Sans-IO state machine running the handshake and then the transport. Bytes
+// from the peer go into `handle_input`, bytes for the peer come out of
+// `poll_output`. Data given to `send` before the handshake has finished is
+// queued and sent once it has. Messages are at most `N` bytes, and each of
+// the output, pending data and event queues holds up to `Q` entries.
+// Transport messages are records, see `RecordType`, of type data or close.
+pub struct Connection<const N: usize, const Q: usize> {
+    state: State,
+    output: Queue<N, Q>,
+    pending: Queue<N, Q>,
+    events: Queue<N, Q>,
+}
+
+impl<const N: usize, const Q: usize> Connection<N, Q> {
+    pub fn new(handshake: Handshake) -> Result<Self, Error> {
+        let mut conn = Self {
+            state: State::Handshake(handshake),
+            output: Queue::new(),
+            pending: Queue::new(),
+            events: Queue::new(),
+        };
+        conn.advance()?;
+        Ok(conn)
+    }
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, State::Established(_))
+    }
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+    pub fn transport(&mut self) -> Option<&mut Transport> {
+        match &mut self.state {
+            State::Established(transport) => Some(transport),
+            _ => None,
+        }
+    }
+    // Writes our handshake messages while it is our turn and moves to the
+    // transport once the handshake is done.
+    fn advance(&mut self) -> Result<(), Error> {
+        if let State::Handshake(handshake) = &mut self.state {
+            if handshake.is_my_turn() {
+                self.output
+                    .push_with(0, |message| handshake.write_message(&[], message))?;
+            }
+        }
+        let done = matches!(&self.state, State::Handshake(h) if h.is_finished());
+        if !done {
+            return Ok(());
+        }
+        let transport = match core::mem::replace(&mut self.state, State::Closed) {
+            State::Handshake(handshake) => handshake.upgrade()?,
+            _ => unreachable!(),
+        };
+        self.events
+            .push(EVENT_COMPLETE, transport.remote_key().as_bytes())?;
+        self.state = State::Established(transport);
+        self.flush_pending()
+    }
+    // Moves pending data to the output queue while it has room, the rest
+    // follows as `poll_output` makes room.
+    fn flush_pending(&mut self) -> Result<(), Error> {
+        let transport = match &mut self.state {
+            State::Established(transport) => transport,
+            _ => return Ok(()),
+        };
+        while self.output.free() > 0 {
+            let Some((_, data)) = self.pending.peek() else {
+                break;
+            };
+            self.output.push_with(0, |message| {
+                transport.write_vectored(&[&[RecordType::Data as u8], data], message)
+            })?;
+            self.pending.pop();
+        }
+        Ok(())
+    }
+    pub fn handle_input(&mut self, message: &[u8]) -> Result<(), Error> {
+        match &mut self.state {
+            State::Handshake(handshake) => {
+                // room for our reply and the payload and completion events,
+                // checked before the handshake moves on
+                if self.output.free() == 0 || self.events.free() < 2 {
+                    return Err(Error::QueueFull);
+                }
+                let mut payload = [0u8; N];
+                let len = handshake.read_message(message, &mut payload)?;
+                if len > 0 {
+                    self.events.push(EVENT_HANDSHAKE_DATA, &payload[..len])?;
+                }
+                self.advance()
+            }
+            State::Established(transport) => {
+                let transport = &mut *transport;
+                let mut closed = false;
+                self.events.push_with(EVENT_DATA, |payload| {
+                    let len = transport.read_message(message, payload)?;
+                    if len == 0 {
+                        return Err(Error::Input);
+                    }
+                    match RecordType::from_u8(payload[0])? {
+                        RecordType::Data => {
+                            payload.copy_within(1..len, 0);
+                            Ok(len - 1)
+                        }
+                        RecordType::Close => {
+                            closed = true;
+                            Ok(0)
+                        }
+                        _ => Err(Error::Input),
+                    }
+                })?;
+                if closed {
+                    // the empty data event becomes the close event
+                    self.events.retag_last(EVENT_CLOSED);
+                    self.state = State::Closed;
+                }
+                Ok(())
+            }
+            State::Closed => Err(Error::Closed),
+        }
+    }
+    // Copies the next message for the peer into `out`. If `out` is too short
+    // it fails with `Error::Input` and the message stays queued, see
+    // `output_len`.
+    pub fn poll_output(&mut self, out: &mut [u8]) -> Result<Option<usize>, Error> {
+        let Some((_, message)) = self.output.peek() else {
+            return Ok(None);
+        };
+        let out = out.get_mut(..message.len()).ok_or(Error::Input)?;
+        out.copy_from_slice(message);
+        self.output.pop();
+        self.flush_pending()?;
+        Ok(Some(out.len()))
+    }
+    // Length of the message `poll_output` returns next.
+    pub fn output_len(&self) -> Option<usize> {
+        self.output.peek().map(|(_, message)| message.len())
+    }
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() + 1 + TAG_LEN > N {
+            return Err(Error::Input);
+        }
+        match &mut self.state {
+            State::Handshake(_) => self.pending.push(0, data),
+            State::Established(transport) => self.output.push_with(0, |message| {
+                transport.write_vectored(&[&[RecordType::Data as u8], data], message)
+            }),
+            State::Closed => Err(Error::Closed),
+        }
+    }
+    // Returns the next event, copying data into `buf`. If `buf` is too
+    // short it fails with `Error::Input` and the event stays queued, see
+    // `event_len`.
+    pub fn poll_event(&mut self, buf: &mut [u8]) -> Result<Option<ConnectionEvent>, Error> {
+        let Some((tag, data)) = self.events.peek() else {
+            return Ok(None);
+        };
+        let event = match tag {
+            EVENT_COMPLETE => {
+                let mut remote_key = [0u8; 32];
+                remote_key.copy_from_slice(data);
+                ConnectionEvent::HandshakeComplete {
+                    remote_key: PublicKey(remote_key),
+                }
+            }
+            EVENT_DATA | EVENT_HANDSHAKE_DATA => {
+                let buf = buf.get_mut(..data.len()).ok_or(Error::Input)?;
+                buf.copy_from_slice(data);
+                if tag == EVENT_DATA {
+                    ConnectionEvent::Data(buf.len())
+                } else {
+                    ConnectionEvent::HandshakeData(buf.len())
+                }
+            }
+            _ => ConnectionEvent::Closed,
+        };
+        self.events.pop();
+        Ok(Some(event))
+    }
+    // Length of the data of the event `poll_event` returns next.
+    pub fn event_len(&self) -> Option<usize> {
+        self.events.peek().map(|(_, data)| data.len())
+    }
+    // Stops accepting data and queues a close record for the peer. Messages
+    // already queued can still be polled. Before the handshake has finished
+    // there is no key to authenticate the close with, so nothing is sent.
+    pub fn close(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            State::Closed => return Ok(()),
+            State::Established(transport) => self.output.push_with(0, |message| {
+                transport.write_vectored(&[&[RecordType::Close as u8]], message)
+            })?,
+            State::Handshake(_) => {}
+        }
+        self.state = State::Closed;
+        // data still waiting for the handshake is dropped
+        while !self.pending.is_empty() {
+            self.pending.pop();
+        }
+        let _ = self.events.push(EVENT_CLOSED, &[]);
+        Ok(())
+    }
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    fn pump<const A: usize, const B: usize>(
+        from: &mut Connection<128, A>,
+        to: &mut Connection<128, B>,
+    ) {
+        let mut buf = [0u8; 128];
+        while let Some(len) = from.poll_output(&mut buf).unwrap() {
+            to.handle_input(&buf[..len]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_connection() {
+        let (init, resp) = handshake_pair();
+        let mut init = Connection::<128, 4>::new(init).unwrap();
+        let mut resp = Connection::<128, 4>::new(resp).unwrap();
+        let mut buf = [0u8; 128];
+
+        init.send(b"early").unwrap();
+        assert!(!init.is_established());
+        pump(&mut init, &mut resp);
+        pump(&mut resp, &mut init);
+        assert!(init.is_established());
+        assert!(matches!(
+            init.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::HandshakeComplete { .. })
+        ));
+        pump(&mut init, &mut resp);
+        assert!(resp.is_established());
+        assert_eq!(
+            resp.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::HandshakeComplete {
+                remote_key: PublicKey([1u8; 32].public_key())
+            })
+        );
+        assert_eq!(
+            resp.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::Data(5))
+        );
+        assert_eq!(&buf[..5], b"early");
+        assert_eq!(resp.poll_event(&mut buf).unwrap(), None);
+
+        // a short buffer leaves the message and the event queued
+        resp.send(b"late").unwrap();
+        let len = resp.output_len().unwrap();
+        assert!(matches!(
+            resp.poll_output(&mut buf[..len - 1]),
+            Err(Error::Input)
+        ));
+        pump(&mut resp, &mut init);
+        assert!(matches!(init.poll_event(&mut buf[..3]), Err(Error::Input)));
+        assert_eq!(init.event_len(), Some(4));
+        assert_eq!(
+            init.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::Data(4))
+        );
+        assert_eq!(&buf[..4], b"late");
+
+        // the peer learns about the close from an authenticated record
+        init.close().unwrap();
+        assert!(matches!(init.send(b"x"), Err(Error::Closed)));
+        assert_eq!(
+            init.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::Closed)
+        );
+        pump(&mut init, &mut resp);
+        assert!(resp.is_closed());
+        assert_eq!(
+            resp.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::Closed)
+        );
+    }
+
+    #[test]
+    fn test_connection_queues() {
+        let (init, resp) = handshake_pair();
+        let mut init = Connection::<128, 2>::new(init).unwrap();
+        let mut resp = Connection::<128, 4>::new(resp).unwrap();
+        let mut buf = [0u8; 128];
+
+        init.send(b"one").unwrap();
+        init.send(b"two").unwrap();
+        assert!(matches!(init.send(b"three"), Err(Error::QueueFull)));
+        pump(&mut init, &mut resp);
+        // message 3 and the first data message fill the output queue, the
+        // second follows once there is room
+        pump(&mut resp, &mut init);
+        pump(&mut init, &mut resp);
+        for data in [b"one", b"two"] {
+            loop {
+                match resp.poll_event(&mut buf).unwrap() {
+                    Some(ConnectionEvent::Data(len)) => {
+                        assert_eq!(&buf[..len], data);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => panic!("missing data"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_handshake_payload() {
+        let (mut init, resp) = handshake_pair();
+        let mut resp = Connection::<128, 4>::new(resp).unwrap();
+        let mut buf = [0u8; 128];
+        let len = init.write_message(b"hello", &mut buf).unwrap();
+        resp.handle_input(&buf[..len]).unwrap();
+        assert_eq!(
+            resp.poll_event(&mut buf).unwrap(),
+            Some(ConnectionEvent::HandshakeData(5))
+        );
+        assert_eq!(&buf[..5], b"hello");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::Error;
+
+// id (u16 LE), index, count
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+pub const MAX_FRAGMENTS: usize = 64;
+
+// Splits a handshake or transport message into fragments of at most `mtu`
+// bytes. Every fragment but the last carries exactly `mtu - 4` bytes.
+pub struct Fragments<'a> {
+    message: &'a [u8],
+    id: u16,
+    chunk: usize,
+    index: usize,
+    count: usize,
+}
+
+impl<'a> Fragments<'a> {
+    pub fn new(message: &'a [u8], id: u16, mtu: usize) -> Result<Self, Error> {
+        if mtu <= FRAGMENT_HEADER_LEN {
+            return Err(Error::Input);
+        }
+        let chunk = mtu - FRAGMENT_HEADER_LEN;
+        let count = message.len().div_ceil(chunk).max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(Error::Input);
+        }
+        Ok(Self {
+            message,
+            id,
+            chunk,
+            index: 0,
+            count,
+        })
+    }
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    // Writes the next fragment into `out`, which must hold `mtu` bytes.
+    pub fn next_fragment(&mut self, out: &mut [u8]) -> Option<usize> {
+        if self.index == self.count {
+            return None;
+        }
+        let start = self.index * self.chunk;
+        let end = (start + self.chunk).min(self.message.len());
+        let len = FRAGMENT_HEADER_LEN + end - start;
+        let out = out.get_mut(..len)?;
+        out[..2].copy_from_slice(&self.id.to_le_bytes());
+        out[2] = self.index as u8;
+        out[3] = self.count as u8;
+        out[FRAGMENT_HEADER_LEN..].copy_from_slice(&self.message[start..end]);
+        self.index += 1;
+        Some(len)
+    }
+}
+
+// Reassembles one message at a time into an `N` byte buffer, which bounds
+// the memory spent per peer. A fragment of a different message id discards
+// the incomplete one, as does `expire` once `timeout` has passed.
+pub struct Reassembler<const N: usize> {
+    buf: [u8; N],
+    chunk: usize,
+    timeout: u64,
+    id: Option<u16>,
+    count: usize,
+    received: u64,
+    len: usize,
+    started: u64,
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub fn new(mtu: usize, timeout: u64) -> Self {
+        Self {
+            buf: [0; N],
+            chunk: mtu.saturating_sub(FRAGMENT_HEADER_LEN),
+            timeout,
+            id: None,
+            count: 0,
+            received: 0,
+            len: 0,
+            started: 0,
+        }
+    }
+    pub fn expire(&mut self, now: u64) {
+        if self.id.is_some() && now.saturating_sub(self.started) >= self.timeout {
+            self.id = None;
+        }
+    }
+    pub fn push(&mut self, fragment: &[u8], now: u64) -> Result<Option<&[u8]>, Error> {
+        if fragment.len() < FRAGMENT_HEADER_LEN || self.chunk == 0 {
+            return Err(Error::Input);
+        }
+        let id = u16::from_le_bytes([fragment[0], fragment[1]]);
+        let index = fragment[2] as usize;
+        let count = fragment[3] as usize;
+        let data = &fragment[FRAGMENT_HEADER_LEN..];
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            return Err(Error::Input);
+        }
+        let last = index + 1 == count;
+        if data.len() > self.chunk || (!last && data.len() != self.chunk) {
+            return Err(Error::Input);
+        }
+        let start = index * self.chunk;
+        if start + data.len() > N {
+            return Err(Error::Input);
+        }
+
+        self.expire(now);
+        if self.id != Some(id) || self.count != count {
+            self.id = Some(id);
+            self.count = count;
+            self.received = 0;
+            self.len = 0;
+            self.started = now;
+        }
+        self.buf[start..start + data.len()].copy_from_slice(data);
+        self.received |= 1 << index;
+        if last {
+            self.len = start + data.len();
+        }
+
+        if self.received.count_ones() as usize == count {
+            self.id = None;
+            Ok(Some(&self.buf[..self.len]))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    #[test]
+    fn test_fragment_handshake_message() {
+        let (mut init, mut resp) = handshake_pair();
+        let mut buf = [0u8; 200];
+        let mut payload = [0u8; 200];
+        exchange(&mut init, &mut resp);
+        let len = resp.write_message(b"config", &mut buf).unwrap();
+
+        let mut fragments = Fragments::new(&buf[..len], 7, 51).unwrap();
+        assert_eq!(fragments.count(), 3);
+        let mut frames = [[0u8; 51]; 3];
+        let mut lens = [0; 3];
+        for (frame, len) in frames.iter_mut().zip(lens.iter_mut()) {
+            *len = fragments.next_fragment(frame).unwrap();
+        }
+        assert!(fragments.next_fragment(&mut [0u8; 51]).is_none());
+
+        let mut reassembler = Reassembler::<256>::new(51, 1_000);
+        assert!(reassembler
+            .push(&frames[2][..lens[2]], 0)
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .push(&frames[0][..lens[0]], 0)
+            .unwrap()
+            .is_none());
+        let message = reassembler.push(&frames[1][..lens[1]], 0).unwrap().unwrap();
+        let len = init.read_message(message, &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"config");
+    }
+
+    #[test]
+    fn test_fragment_transport_message() {
+        let (mut init, mut resp) = transport_pair();
+        let mut buf = [0u8; 200];
+        let mut payload = [0u8; 200];
+        let len = init.write_message(&[7u8; 100], &mut buf).unwrap();
+
+        let mut fragments = Fragments::new(&buf[..len], 8, 24).unwrap();
+        assert_eq!(fragments.count(), 6);
+        let mut reassembler = Reassembler::<256>::new(24, 1_000);
+        let mut frame = [0u8; 24];
+        let mut message = None;
+        while let Some(len) = fragments.next_fragment(&mut frame) {
+            message = reassembler.push(&frame[..len], 0).unwrap();
+        }
+        let len = resp.read_message(message.unwrap(), &mut payload).unwrap();
+        assert_eq!(&payload[..len], &[7u8; 100]);
+    }
+
+    #[test]
+    fn test_reassembler_limits() {
+        let message = [5u8; 100];
+        let mut fragments = Fragments::new(&message, 1, 24).unwrap();
+        let mut frame = [0u8; 24];
+
+        let mut reassembler = Reassembler::<64>::new(24, 1_000);
+        let len = fragments.next_fragment(&mut frame).unwrap();
+        assert!(reassembler.push(&frame[..len], 0).unwrap().is_none());
+        // does not fit the per-peer buffer
+        for _ in 0..3 {
+            fragments.next_fragment(&mut frame).unwrap();
+        }
+        assert!(matches!(reassembler.push(&frame, 0), Err(Error::Input)));
+
+        // an incomplete message times out
+        let mut fragments = Fragments::new(&message[..40], 2, 24).unwrap();
+        let len = fragments.next_fragment(&mut frame).unwrap();
+        reassembler.push(&frame[..len], 0).unwrap();
+        let len = fragments.next_fragment(&mut frame).unwrap();
+        assert!(reassembler.push(&frame[..len], 1_000).unwrap().is_none());
+        let mut fragments = Fragments::new(&message[..40], 2, 24).unwrap();
+        let len = fragments.next_fragment(&mut frame).unwrap();
+        assert_eq!(
+            reassembler.push(&frame[..len], 1_000).unwrap().unwrap(),
+            &message[..40]
+        );
+    }
+}
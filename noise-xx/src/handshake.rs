@@ -1,13 +1,20 @@
+#[cfg(feature = "keylog")]
+use crate::keylog::{
+    KeyLogFn, KEYLOG_HANDSHAKE_HASH, KEYLOG_INITIATOR_KEY, KEYLOG_MSG2_KEY, KEYLOG_MSG3_KEY,
+    KEYLOG_RESPONDER_KEY,
+};
 use crate::{
     cipher_state::TAG_LEN,
-    x25519::{pub_key, x25519},
-    Error, SymmetricState, Transport,
+    observer::{observe, observe_result},
+    padding,
+    x25519::{check_dh, pub_key, x25519},
+    Error, Event, Observer, Padding, PublicKey, StaticKey, SymmetricState, Transport,
 };
 
 pub(crate) const DH_LEN: usize = 32;
 type DHKey = [u8; 32];
 
-mod handshake_state {
+pub(crate) mod handshake_state {
     pub enum HandshakeState {
         I1,
         I2,
@@ -17,6 +24,9 @@ mod handshake_state {
         R3,
         IDone,
         RDone,
+        // a payload check failed after the message was read, see `poison`
+        #[cfg_attr(not(any(feature = "cert", feature = "std")), allow(dead_code))]
+        Failed,
     }
     impl HandshakeState {
         pub fn overhead(&self) -> usize {
@@ -24,7 +34,20 @@ mod handshake_state {
                 Self::I1 | Self::R1 => 32,
                 Self::I2 | Self::R2 => 96,
                 Self::I3 | Self::R3 => 64,
-                Self::IDone | Self::RDone => todo!(),
+                Self::IDone | Self::RDone | Self::Failed => 0,
+            }
+        }
+        // number of the message read or written in this state
+        pub fn message(&self) -> u8 {
+            match self {
+                Self::I1 | Self::R1 => 1,
+                Self::I2 | Self::R2 => 2,
+                Self::I3 | Self::R3 => 3,
+                // only asked for while reading or writing a message, which
+                // fails first once the handshake is finished or poisoned
+                Self::IDone | Self::RDone | Self::Failed => {
+                    unreachable!("no message after a finished or failed handshake")
+                }
             }
         }
         pub fn next(&mut self) {
@@ -35,30 +58,132 @@ mod handshake_state {
                 Self::R1 => *self = Self::R2,
                 Self::R2 => *self = Self::R3,
                 Self::R3 => *self = Self::RDone,
-                Self::IDone => todo!(),
-                Self::RDone => todo!(),
+                // read_message and write_message fail before advancing a
+                // finished or poisoned handshake, and a poisoned one must
+                // never turn back into a usable state
+                Self::IDone | Self::RDone | Self::Failed => {
+                    unreachable!("advancing a finished or failed handshake")
+                }
             }
         }
     }
 }
 use handshake_state::HandshakeState::{self, *};
 
+// Tokens a handshake variant adds after `e` in message 1 and after `ee` in
+// message 2, see `HybridHandshake`.
+pub(crate) trait Tokens {
+    // bytes added to message 1, 2 or 3
+    fn len(&self, message: u8) -> usize;
+    fn write(&mut self, message: u8, sym: &mut SymmetricState, out: &mut [u8])
+        -> Result<(), Error>;
+    // `scratch` is the caller's payload buffer, unused until the payload
+    fn read(
+        &mut self,
+        message: u8,
+        sym: &mut SymmetricState,
+        tokens: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<(), Error>;
+}
+
+// plain XX
+pub(crate) struct NoTokens;
+
+impl Tokens for NoTokens {
+    fn len(&self, _: u8) -> usize {
+        0
+    }
+    fn write(&mut self, _: u8, _: &mut SymmetricState, _: &mut [u8]) -> Result<(), Error> {
+        Ok(())
+    }
+    fn read(&mut self, _: u8, _: &mut SymmetricState, _: &[u8], _: &mut [u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
 //
 //  -> e
 //  <- e, ee, s, es
 //  -> s, se
-pub struct Handshake {
+// `K` holds the static key, by default the raw secret, see `StaticKey`.
+pub struct Handshake<K: StaticKey = DHKey> {
     e: [u8; DH_LEN],
-    s: [u8; DH_LEN],
+    s: K,
     re: [u8; DH_LEN],
     rs: [u8; DH_LEN],
     state: HandshakeState,
     sym: SymmetricState,
+    padding: Padding,
+    observer: Option<&'static dyn Observer>,
+    #[cfg(feature = "keylog")]
+    keylog: Option<KeyLogFn>,
+}
+
+// Stands in for the static key until `DeferredResponder::select` is called.
+// The responder never needs `s` before writing message 2.
+struct Unselected;
+
+impl StaticKey for Unselected {
+    fn public_key(&self) -> [u8; 32] {
+        unreachable!()
+    }
+    fn dh(&mut self, _: &[u8; 32]) -> Result<[u8; 32], Error> {
+        Err(Error::NotMyTurn)
+    }
 }
 
-impl Handshake {
-    pub fn new(init: bool, e: DHKey, s: DHKey, prologue: &[u8]) -> Self {
-        let mut sym = SymmetricState::new();
+// Responder that picks its static key after reading message 1, e.g. by the
+// tenant id in its payload. `s` is first mixed into the transcript in
+// message 2, so the transcript is the same as with `Handshake::resp`.
+pub struct DeferredResponder(Handshake<Unselected>);
+
+impl DeferredResponder {
+    pub fn new(e: DHKey, prologue: &[u8]) -> Self {
+        Self(Handshake::resp(e, Unselected, prologue))
+    }
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.0.set_padding(padding)
+    }
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.0.set_observer(observer)
+    }
+    // Reads message 1.
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        self.0.read_message(message, payload)
+    }
+    // Continues as a normal responder once message 1 has been read.
+    pub fn select<K: StaticKey>(self, s: K) -> Result<Handshake<K>, Error> {
+        let h = self.0;
+        if !matches!(h.state, R2) {
+            return Err(Error::NotMyTurn);
+        }
+        Ok(Handshake {
+            e: h.e,
+            s,
+            re: h.re,
+            rs: h.rs,
+            state: h.state,
+            sym: h.sym,
+            padding: h.padding,
+            observer: h.observer,
+            #[cfg(feature = "keylog")]
+            keylog: h.keylog,
+        })
+    }
+}
+
+impl<K: StaticKey> Handshake<K> {
+    pub fn new(init: bool, e: DHKey, s: K, prologue: &[u8]) -> Self {
+        Self::with_sym(init, e, s, SymmetricState::new(), prologue)
+    }
+    pub(crate) fn with_sym(
+        init: bool,
+        e: DHKey,
+        s: K,
+        mut sym: SymmetricState,
+        prologue: &[u8],
+    ) -> Self {
         sym.mix_hash(prologue);
         Self {
             e,
@@ -67,87 +192,228 @@ impl Handshake {
             rs: [0; 32],
             state: if init { I1 } else { R1 },
             sym,
+            padding: Padding::None,
+            observer: None,
+            #[cfg(feature = "keylog")]
+            keylog: None,
         }
     }
-    pub fn init(e: DHKey, s: DHKey, prologue: &[u8]) -> Self {
+    pub fn init(e: DHKey, s: K, prologue: &[u8]) -> Self {
         Self::new(true, e, s, prologue)
     }
-    pub fn resp(e: DHKey, s: DHKey, prologue: &[u8]) -> Self {
+    pub fn resp(e: DHKey, s: K, prologue: &[u8]) -> Self {
         Self::new(false, e, s, prologue)
     }
+    // Pads handshake payloads and, after `upgrade`, transport messages.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+    // Reports this handshake's events and, after `upgrade`, the transport's.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+    fn next_state(&mut self) {
+        let initiator = matches!(self.state, I1 | I2 | I3);
+        let message = self.state.message();
+        self.state.next();
+        observe(self.observer, Event::Handshake { initiator, message });
+    }
+    // Hands session secrets to `f`, see `KeyLogFn`. Debug builds only.
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&mut self, f: KeyLogFn) {
+        self.keylog = Some(f);
+    }
+    // sessions are named by the initiator's ephemeral public key
+    #[cfg(feature = "keylog")]
+    fn log(&self, label: &str, secret: &[u8; 32]) {
+        if let Some(f) = self.keylog {
+            let session = match self.state {
+                I1 | I2 | I3 | IDone => pub_key(self.e),
+                _ => self.re,
+            };
+            f(label, &session, secret);
+        }
+    }
+    // logs the key of the payload just read or written
+    #[cfg(feature = "keylog")]
+    fn log_payload_key(&self) {
+        let label = match self.state {
+            I2 | R2 => KEYLOG_MSG2_KEY,
+            I3 | R3 => KEYLOG_MSG3_KEY,
+            _ => return,
+        };
+        self.log(label, &self.sym.cipher_key());
+    }
+    // Fails every later call, for when the peer was rejected after its
+    // message had already been read.
+    #[cfg_attr(not(any(feature = "cert", feature = "std")), allow(dead_code))]
+    pub(crate) fn poison(&mut self) {
+        self.state = Failed;
+    }
+    pub fn is_my_turn(&self) -> bool {
+        matches!(self.state, I1 | R2 | I3)
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, IDone | RDone)
+    }
+    pub fn remote_key(&self) -> Option<PublicKey> {
+        match self.state {
+            I3 | IDone | RDone => Some(PublicKey(self.rs)),
+            _ => None,
+        }
+    }
+    pub fn handshake_hash(&self) -> Result<[u8; 32], Error> {
+        match self.state {
+            IDone | RDone => Ok(self.sym.handshake_hash()),
+            _ => Err(Error::NotMyTurn),
+        }
+    }
     pub fn upgrade(self) -> Result<Transport, Error> {
-        let (send, recv) = match self.state {
-            IDone => self.sym.split(),
+        let ex = self.sym.exporter_secret();
+        let (pad_i, pad_r) = self.sym.padding_keys();
+        #[cfg(feature = "keylog")]
+        if matches!(self.state, IDone | RDone) {
+            let (k1, k2) = self.sym.split_keys();
+            self.log(KEYLOG_HANDSHAKE_HASH, &self.sym.handshake_hash());
+            self.log(KEYLOG_INITIATOR_KEY, &k1);
+            self.log(KEYLOG_RESPONDER_KEY, &k2);
+        }
+        let (send, recv, pad_key) = match self.state {
+            IDone => {
+                let (c1, c2) = self.sym.split();
+                (c1, c2, pad_i)
+            }
             RDone => {
                 let (c1, c2) = self.sym.split();
-                (c2, c1)
+                (c2, c1, pad_r)
             }
+            Failed => return Err(Error::Poisoned),
             _ => return Err(Error::NotMyTurn),
         };
         Ok(Transport {
             rs: self.rs,
+            ex,
+            padding: self.padding,
+            pad_key,
+            observer: self.observer,
             send,
             recv,
         })
     }
     pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
-        if message.len() < self.state.overhead() {
+        self.read_message_with(&mut NoTokens, message, payload)
+    }
+    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        self.write_message_with(&mut NoTokens, payload, message)
+    }
+    pub(crate) fn read_message_with(
+        &mut self,
+        tokens: &mut impl Tokens,
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        match self.state {
+            IDone | RDone => return Err(Error::NeedUpgrade),
+            Failed => return Err(Error::Poisoned),
+            _ => {}
+        }
+        let overhead = self.state.overhead() + tokens.len(self.state.message());
+        if message.len() < overhead {
             return Err(Error::Input);
         }
-        if payload.len() < message.len() - self.state.overhead() {
+        if payload.len() < message.len() - overhead {
             return Err(Error::Input);
         }
 
         let prev_sym = self.sym.clone();
-        let result = self._read_message(message, payload);
+        let result = self
+            ._read_message(tokens, message, payload)
+            .and_then(|len| self.padding.unpad(payload, len));
         if result.is_ok() {
-            self.state.next();
+            #[cfg(feature = "keylog")]
+            self.log_payload_key();
+            self.next_state();
         } else {
             self.sym = prev_sym;
         }
+        observe_result(self.observer, &result);
         result
     }
-    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
-        if message.len() < self.state.overhead() + payload.len() {
+    pub(crate) fn write_message_with(
+        &mut self,
+        tokens: &mut impl Tokens,
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        // where the payload goes in the message
+        let offset = match self.state {
+            I1 => DH_LEN,
+            R2 => DH_LEN + DH_LEN + TAG_LEN,
+            I3 => DH_LEN + TAG_LEN,
+            R1 | I2 | R3 => return Err(Error::NotMyTurn),
+            IDone | RDone => return Err(Error::NeedUpgrade),
+            Failed => return Err(Error::Poisoned),
+        } + tokens.len(self.state.message());
+        let pad_key = padding::derive_key(&self.e, b"handshake padding");
+        let padded_len = self
+            .padding
+            .padded_len(payload.len(), &pad_key, offset as u64)?;
+        let overhead = self.state.overhead() + tokens.len(self.state.message());
+        if message.len() < overhead + padded_len {
             return Err(Error::Input);
         }
+        message[offset..offset + payload.len()].copy_from_slice(payload);
+        self.padding
+            .pad(&mut message[offset..], payload.len(), padded_len)?;
         let prev_sym = self.sym.clone();
 
-        let result = self._write_message(payload, message);
+        let result = self._write_message(tokens, padded_len, message);
 
         if result.is_ok() {
-            self.state.next();
+            #[cfg(feature = "keylog")]
+            self.log_payload_key();
+            self.next_state();
         } else {
             self.sym = prev_sym;
         }
+        observe_result(self.observer, &result);
         result
     }
-    fn _read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+    fn _read_message(
+        &mut self,
+        tokens: &mut impl Tokens,
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
         match self.state {
             I1 | R2 | I3 => Err(Error::NotMyTurn),
-            IDone | RDone => Err(Error::NeedUpgrade),
+            IDone | RDone | Failed => Err(Error::NeedUpgrade),
             R1 => {
-                let (msg_re, msg_rp) = message.split_at(DH_LEN);
-                let (payload, _) = payload.split_at_mut(msg_rp.len());
+                let (msg_re, rest) = message.split_at(DH_LEN);
+                let (msg_t, msg_rp) = rest.split_at(tokens.len(1));
                 // e
                 self.sym.decrypt_and_hash(msg_re, &mut self.re)?;
 
+                tokens.read(1, &mut self.sym, msg_t, payload)?;
+
                 // payload
+                let (payload, _) = payload.split_at_mut(msg_rp.len());
                 self.sym.decrypt_and_hash(msg_rp, payload)?;
                 Ok(payload.len())
             }
             I2 => {
                 let (msg_e, rest) = message.split_at(DH_LEN);
+                let (msg_t, rest) = rest.split_at(tokens.len(2));
                 let (msg_s, msg_p) = rest.split_at(DH_LEN + TAG_LEN);
 
-                let (payload, _) = payload.split_at_mut(msg_p.len() - TAG_LEN);
-
                 // e
                 self.sym.decrypt_and_hash(msg_e, &mut self.re)?;
 
                 // ee
                 self.sym.mix_key(&x25519(self.e, self.re)?);
 
+                tokens.read(2, &mut self.sym, msg_t, payload)?;
+
                 // s
                 self.sym.decrypt_and_hash(msg_s, &mut self.rs)?;
 
@@ -155,6 +421,7 @@ impl Handshake {
                 self.sym.mix_key(&x25519(self.e, self.rs)?);
 
                 // payload
+                let (payload, _) = payload.split_at_mut(msg_p.len() - TAG_LEN);
                 self.sym.decrypt_and_hash(msg_p, payload)?;
                 Ok(payload.len())
             }
@@ -175,26 +442,36 @@ impl Handshake {
             }
         }
     }
-    fn _write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+    // The payload is already in place, see `write_message`.
+    fn _write_message(
+        &mut self,
+        tokens: &mut impl Tokens,
+        payload_len: usize,
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
         match self.state {
             R1 | I2 | R3 => Err(Error::NotMyTurn),
-            IDone | RDone => Err(Error::NeedUpgrade),
+            IDone | RDone | Failed => Err(Error::NeedUpgrade),
             I1 => {
                 let (msg_e, rest) = message.split_at_mut(DH_LEN);
-                let (msg_p, _) = &mut rest.split_at_mut(payload.len());
+                let (msg_t, rest) = rest.split_at_mut(tokens.len(1));
+                let (msg_p, _) = &mut rest.split_at_mut(payload_len);
 
                 // e
                 self.sym.encrypt_and_hash(&pub_key(self.e), msg_e)?;
 
+                tokens.write(1, &mut self.sym, msg_t)?;
+
                 // payload
-                self.sym.encrypt_and_hash(payload, msg_p)?;
+                self.sym.encrypt_and_hash_in_place(msg_p, payload_len)?;
 
-                Ok(msg_e.len() + msg_p.len())
+                Ok(msg_e.len() + msg_t.len() + msg_p.len())
             }
             R2 => {
                 let (msg_e, rest) = message.split_at_mut(DH_LEN);
+                let (msg_t, rest) = rest.split_at_mut(tokens.len(2));
                 let (msg_s, rest) = rest.split_at_mut(DH_LEN + TAG_LEN);
-                let (msg_p, _) = rest.split_at_mut(payload.len() + TAG_LEN);
+                let (msg_p, _) = rest.split_at_mut(payload_len + TAG_LEN);
 
                 // e
                 self.sym.encrypt_and_hash(&pub_key(self.e), msg_e)?;
@@ -202,28 +479,30 @@ impl Handshake {
                 // ee
                 self.sym.mix_key(&x25519(self.e, self.re)?);
 
+                tokens.write(2, &mut self.sym, msg_t)?;
+
                 // s
-                self.sym.encrypt_and_hash(&pub_key(self.s), msg_s)?;
+                self.sym.encrypt_and_hash(&self.s.public_key(), msg_s)?;
 
                 // es
-                self.sym.mix_key(&x25519(self.s, self.re)?);
+                self.sym.mix_key(&check_dh(self.s.dh(&self.re)?)?);
 
                 // payload
-                self.sym.encrypt_and_hash(payload, msg_p)?;
-                Ok(msg_e.len() + msg_s.len() + msg_p.len())
+                self.sym.encrypt_and_hash_in_place(msg_p, payload_len)?;
+                Ok(msg_e.len() + msg_t.len() + msg_s.len() + msg_p.len())
             }
             I3 => {
                 let (msg_s, rest) = message.split_at_mut(DH_LEN + TAG_LEN);
-                let (msg_p, _) = rest.split_at_mut(payload.len() + TAG_LEN);
+                let (msg_p, _) = rest.split_at_mut(payload_len + TAG_LEN);
 
                 // s
-                self.sym.encrypt_and_hash(&pub_key(self.s), msg_s)?;
+                self.sym.encrypt_and_hash(&self.s.public_key(), msg_s)?;
 
                 // se
-                self.sym.mix_key(&x25519(self.s, self.re)?);
+                self.sym.mix_key(&check_dh(self.s.dh(&self.re)?)?);
 
                 // payload
-                self.sym.encrypt_and_hash(payload, msg_p)?;
+                self.sym.encrypt_and_hash_in_place(msg_p, payload_len)?;
                 Ok(msg_s.len() + msg_p.len())
             }
         }
@@ -0,0 +1,453 @@
+#[cfg(feature = "keylog")]
+use crate::KeyLogFn;
+use crate::{
+    cipher_state::TAG_LEN,
+    handshake::{Tokens, DH_LEN},
+    Error, Handshake, Observer, Padding, PublicKey, StaticKey, SymmetricState, Transport,
+};
+
+// Key encapsulation used for the hybrid forward secrecy of `HybridHandshake`.
+// A value is the initiator's ephemeral KEM key pair. The responder only
+// encapsulates, with randomness `m` from the caller.
+pub trait Kem {
+    const PROT_NAME: &'static str;
+    const PUBLIC_KEY_LEN: usize;
+    const CIPHERTEXT_LEN: usize;
+    type PublicKey: AsRef<[u8]> + for<'a> TryFrom<&'a [u8]>;
+    type Ciphertext: AsRef<[u8]> + for<'a> TryFrom<&'a [u8]>;
+    fn public_key(&self) -> Self::PublicKey;
+    // `m` must be fresh random bytes, encapsulating twice with the same `m`
+    // gives the same shared secret.
+    fn encapsulate(
+        public_key: &Self::PublicKey,
+        m: [u8; 32],
+    ) -> Result<(Self::Ciphertext, [u8; 32]), Error>;
+    fn decapsulate(&self, ciphertext: &Self::Ciphertext) -> Result<[u8; 32], Error>;
+}
+
+//
+//  -> e, e1
+//  <- e, ee, ekem1, s, es
+//  -> s, se
+//
+// XX with an ephemeral KEM key e1 sent in message 1 and the responder's
+// encapsulation to it in message 2, whose shared secret is mixed in after
+// ee. The session stays confidential unless both X25519 and the KEM break.
+pub struct HybridHandshake<M: Kem, K: StaticKey = [u8; 32]> {
+    handshake: Handshake<K>,
+    tokens: KemTokens<M>,
+}
+
+// e1 and ekem1. `m` is dropped once used, so it cannot encapsulate twice.
+struct KemTokens<M: Kem> {
+    // initiator
+    kem: Option<M>,
+    // responder
+    m: Option<[u8; 32]>,
+    re1: Option<M::PublicKey>,
+}
+
+impl<M: Kem> Tokens for KemTokens<M> {
+    fn len(&self, message: u8) -> usize {
+        match message {
+            1 => M::PUBLIC_KEY_LEN,
+            2 => M::CIPHERTEXT_LEN + TAG_LEN,
+            _ => 0,
+        }
+    }
+    fn write(
+        &mut self,
+        message: u8,
+        sym: &mut SymmetricState,
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        match message {
+            1 => {
+                // e1
+                let e1 = self.kem.as_ref().ok_or(Error::NotMyTurn)?.public_key();
+                sym.encrypt_and_hash(e1.as_ref(), out)?;
+            }
+            2 => {
+                // ekem1
+                let re1 = self.re1.as_ref().ok_or(Error::NotMyTurn)?;
+                let m = self.m.take().ok_or(Error::NotMyTurn)?;
+                let (ct, ss) = M::encapsulate(re1, m)?;
+                sym.encrypt_and_hash(ct.as_ref(), out)?;
+                sym.mix_key(&ss);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn read(
+        &mut self,
+        message: u8,
+        sym: &mut SymmetricState,
+        tokens: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<(), Error> {
+        match message {
+            1 => {
+                // e1, no key yet so it is only hashed
+                sym.mix_hash(tokens);
+                self.re1 = Some(tokens.try_into().map_err(|_| Error::Input)?);
+            }
+            2 => {
+                // ekem1, decrypted in place of the payload which comes later
+                let ct = scratch.get_mut(..M::CIPHERTEXT_LEN).ok_or(Error::Input)?;
+                sym.decrypt_and_hash(tokens, ct)?;
+                let ct = M::Ciphertext::try_from(ct).map_err(|_| Error::Input)?;
+                let kem = self.kem.as_ref().ok_or(Error::NotMyTurn)?;
+                sym.mix_key(&kem.decapsulate(&ct)?);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<M: Kem, K: StaticKey> HybridHandshake<M, K> {
+    // Bytes each of the three messages adds to its payload, without padding.
+    pub const OVERHEAD: [usize; 3] = [
+        DH_LEN + M::PUBLIC_KEY_LEN,
+        DH_LEN + M::CIPHERTEXT_LEN + TAG_LEN + DH_LEN + TAG_LEN + TAG_LEN,
+        DH_LEN + TAG_LEN + TAG_LEN,
+    ];
+
+    fn new(init: bool, e: [u8; DH_LEN], s: K, tokens: KemTokens<M>, prologue: &[u8]) -> Self {
+        let sym = SymmetricState::with_protocol_name(M::PROT_NAME.as_bytes());
+        Self {
+            handshake: Handshake::with_sym(init, e, s, sym, prologue),
+            tokens,
+        }
+    }
+    // `kem` is a fresh ephemeral KEM key pair.
+    pub fn init(e: [u8; DH_LEN], s: K, kem: M, prologue: &[u8]) -> Self {
+        let tokens = KemTokens {
+            kem: Some(kem),
+            m: None,
+            re1: None,
+        };
+        Self::new(true, e, s, tokens, prologue)
+    }
+    // `m` is fresh randomness for the encapsulation, see `Kem::encapsulate`.
+    pub fn resp(e: [u8; DH_LEN], s: K, m: [u8; 32], prologue: &[u8]) -> Self {
+        let tokens = KemTokens {
+            kem: None,
+            m: Some(m),
+            re1: None,
+        };
+        Self::new(false, e, s, tokens, prologue)
+    }
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.handshake.set_padding(padding);
+    }
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.handshake.set_observer(observer);
+    }
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&mut self, f: KeyLogFn) {
+        self.handshake.set_keylog(f);
+    }
+    pub fn is_my_turn(&self) -> bool {
+        self.handshake.is_my_turn()
+    }
+    pub fn is_finished(&self) -> bool {
+        self.handshake.is_finished()
+    }
+    pub fn remote_key(&self) -> Option<PublicKey> {
+        self.handshake.remote_key()
+    }
+    pub fn handshake_hash(&self) -> Result<[u8; 32], Error> {
+        self.handshake.handshake_hash()
+    }
+    pub fn upgrade(self) -> Result<Transport, Error> {
+        self.handshake.upgrade()
+    }
+    // For message 2 `payload` must hold at least CIPHERTEXT_LEN bytes, it is
+    // used to decrypt the KEM ciphertext.
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        self.handshake
+            .read_message_with(&mut self.tokens, message, payload)
+    }
+    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        self.handshake
+            .write_message_with(&mut self.tokens, payload, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    // DH-based KEM standing in for ML-KEM, only to drive the handshake.
+    struct DhKem {
+        secret: [u8; 32],
+    }
+
+    impl Kem for DhKem {
+        const PROT_NAME: &'static str = "Noise_XXhfs_25519+DHKEM_ChaChaPoly_BLAKE2s";
+        const PUBLIC_KEY_LEN: usize = 32;
+        const CIPHERTEXT_LEN: usize = 32;
+        type PublicKey = [u8; 32];
+        type Ciphertext = [u8; 32];
+        fn public_key(&self) -> [u8; 32] {
+            self.secret.public_key()
+        }
+        fn encapsulate(public_key: &[u8; 32], m: [u8; 32]) -> Result<([u8; 32], [u8; 32]), Error> {
+            Ok((m.public_key(), crate::x25519::x25519(m, *public_key)?))
+        }
+        fn decapsulate(&self, ciphertext: &[u8; 32]) -> Result<[u8; 32], Error> {
+            crate::x25519::x25519(self.secret, *ciphertext)
+        }
+    }
+
+    type Hybrid = HybridHandshake<DhKem>;
+
+    fn handshake(init: &mut Hybrid, resp: &mut Hybrid, tamper: Option<usize>) -> Result<(), Error> {
+        let mut buf_init = [0u8; 300];
+        let mut buf_resp = [0u8; 300];
+        let len = init.write_message(b"one", &mut buf_init)?;
+        assert_eq!(len, Hybrid::OVERHEAD[0] + 3);
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp)?;
+        assert_eq!(&buf_resp[..len], b"one");
+        let len = resp.write_message(b"two", &mut buf_resp)?;
+        assert_eq!(len, Hybrid::OVERHEAD[1] + 3);
+        if let Some(i) = tamper {
+            buf_resp[i] ^= 1;
+        }
+        let len = init.read_message(&buf_resp[..len], &mut buf_init)?;
+        assert_eq!(&buf_init[..len], b"two");
+        let len = init.write_message(b"three", &mut buf_init)?;
+        assert_eq!(len, Hybrid::OVERHEAD[2] + 5);
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp)?;
+        assert_eq!(&buf_resp[..len], b"three");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hybrid_handshake() {
+        let mut init = Hybrid::init([0u8; 32], [1u8; 32], DhKem { secret: [4u8; 32] }, &[]);
+        let mut resp = Hybrid::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        handshake(&mut init, &mut resp, None).unwrap();
+        assert_eq!(
+            init.handshake_hash().unwrap(),
+            resp.handshake_hash().unwrap()
+        );
+        assert_eq!(resp.remote_key().unwrap(), [1u8; 32].public_key());
+
+        // same DH keys without the KEM give a different transcript
+        let plain = {
+            let mut buf_init = [0u8; 300];
+            let mut buf_resp = [0u8; 300];
+            let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+            let mut resp = Handshake::resp([2u8; 32], [3u8; 32], &[]);
+            let len = init.write_message(b"one", &mut buf_init).unwrap();
+            resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+            let len = resp.write_message(b"two", &mut buf_resp).unwrap();
+            init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+            let len = init.write_message(b"three", &mut buf_init).unwrap();
+            resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+            init.handshake_hash().unwrap()
+        };
+        assert_ne!(init.handshake_hash().unwrap(), plain);
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+        let len = init.write_message(b"pq", &mut message).unwrap();
+        let len = resp.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"pq");
+    }
+
+    #[test]
+    fn test_hybrid_handshake_tampered_ciphertext() {
+        let mut init = Hybrid::init([0u8; 32], [1u8; 32], DhKem { secret: [4u8; 32] }, &[]);
+        let mut resp = Hybrid::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        // first byte of the encrypted KEM ciphertext
+        assert!(matches!(
+            handshake(&mut init, &mut resp, Some(32)),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn test_short_payload_buffer() {
+        let mut init = Hybrid::init([0u8; 32], [1u8; 32], DhKem { secret: [4u8; 32] }, &[]);
+        let mut resp = Hybrid::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        let mut buf = [0u8; 300];
+        let len = init.write_message(&[], &mut buf).unwrap();
+        resp.read_message(&buf[..len], &mut []).unwrap();
+        let len = resp.write_message(&[], &mut buf).unwrap();
+        // the ciphertext needs CIPHERTEXT_LEN bytes of payload buffer
+        assert!(matches!(
+            init.read_message(&buf[..len], &mut [0u8; 31]),
+            Err(Error::Input)
+        ));
+        let mut payload = [0u8; 32];
+        assert_eq!(init.read_message(&buf[..len], &mut payload).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mlkem768_handshake() {
+        type Hybrid = HybridHandshake<MlKem768>;
+        assert_eq!(
+            Hybrid::OVERHEAD,
+            [32 + 1184, 32 + 1088 + 16 + 48 + 16, 48 + 16]
+        );
+
+        let mut init = Hybrid::init([0u8; 32], [1u8; 32], MlKem768::new([4u8; 64]), &[]);
+        let mut resp = Hybrid::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        init.set_padding(Padding::Block(64));
+        resp.set_padding(Padding::Block(64));
+        let mut buf_init = [0u8; 3000];
+        let mut buf_resp = [0u8; 3000];
+        let len = init.write_message(b"one", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"one");
+        let len = resp.write_message(b"two", &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..len], b"two");
+        let len = init.write_message(b"three", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"three");
+        assert_eq!(
+            init.handshake_hash().unwrap(),
+            resp.handshake_hash().unwrap()
+        );
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"pq", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"pq");
+    }
+
+    #[test]
+    fn test_responder_encapsulates_once() {
+        let mut init = Hybrid::init([0u8; 32], [1u8; 32], DhKem { secret: [4u8; 32] }, &[]);
+        let mut resp = Hybrid::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        let mut buf = [0u8; 300];
+        let len = init.write_message(&[], &mut buf).unwrap();
+        resp.read_message(&buf[..len], &mut []).unwrap();
+        resp.tokens.m = None;
+        assert!(matches!(
+            resp.write_message(&[], &mut buf),
+            Err(Error::NotMyTurn)
+        ));
+    }
+
+    // Kyber1024 from PQClean, the KEM snow implements XXhfs with. Its
+    // randomness cannot be supplied, so `m` is ignored.
+    struct Kyber1024 {
+        pk: [u8; 1568],
+        sk: pqcrypto_kyber::kyber1024::SecretKey,
+    }
+
+    impl Kyber1024 {
+        fn new() -> Self {
+            let (pk, sk) = pqcrypto_kyber::kyber1024::keypair();
+            let pk = pqcrypto_traits::kem::PublicKey::as_bytes(&pk);
+            Self {
+                pk: pk.try_into().unwrap(),
+                sk,
+            }
+        }
+    }
+
+    impl Kem for Kyber1024 {
+        const PROT_NAME: &'static str = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s";
+        const PUBLIC_KEY_LEN: usize = 1568;
+        const CIPHERTEXT_LEN: usize = 1568;
+        type PublicKey = [u8; 1568];
+        type Ciphertext = [u8; 1568];
+        fn public_key(&self) -> [u8; 1568] {
+            self.pk
+        }
+        fn encapsulate(
+            public_key: &[u8; 1568],
+            _m: [u8; 32],
+        ) -> Result<([u8; 1568], [u8; 32]), Error> {
+            use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
+            let pk = pqcrypto_kyber::kyber1024::PublicKey::from_bytes(public_key)
+                .map_err(|_| Error::Input)?;
+            let (ss, ct) = pqcrypto_kyber::kyber1024::encapsulate(&pk);
+            Ok((
+                ct.as_bytes().try_into().unwrap(),
+                ss.as_bytes().try_into().unwrap(),
+            ))
+        }
+        fn decapsulate(&self, ciphertext: &[u8; 1568]) -> Result<[u8; 32], Error> {
+            use pqcrypto_traits::kem::{Ciphertext, SharedSecret};
+            let ct = pqcrypto_kyber::kyber1024::Ciphertext::from_bytes(ciphertext)
+                .map_err(|_| Error::Input)?;
+            let ss = pqcrypto_kyber::kyber1024::decapsulate(&ct, &self.sk);
+            Ok(ss.as_bytes().try_into().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_hybrid_init_using_snow() {
+        let mut buf_init = [0u8; 4000];
+        let mut buf_resp = [0u8; 4000];
+        let mut init = HybridHandshake::<_>::init([0u8; 32], [1u8; 32], Kyber1024::new(), b"pro");
+        let mut resp = snow::Builder::new(Kyber1024::PROT_NAME.parse().unwrap())
+            .local_private_key(&[3u8; 32])
+            .fixed_ephemeral_key_for_testing_only(&[2u8; 32])
+            .prologue(b"pro")
+            .build_responder()
+            .unwrap();
+
+        let len = init.write_message(b"one", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"one");
+        let len = resp.write_message(b"two", &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..len], b"two");
+        let len = init.write_message(b"three", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"three");
+        assert_eq!(init.handshake_hash().unwrap(), resp.get_handshake_hash());
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.into_transport_mode().unwrap();
+        let len = init.write_message(b"hello", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"hello");
+        let len = resp.write_message(b"world", &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..len], b"world");
+    }
+
+    #[test]
+    fn test_hybrid_resp_using_snow() {
+        let mut buf_init = [0u8; 4000];
+        let mut buf_resp = [0u8; 4000];
+        let mut init = snow::Builder::new(Kyber1024::PROT_NAME.parse().unwrap())
+            .local_private_key(&[1u8; 32])
+            .fixed_ephemeral_key_for_testing_only(&[0u8; 32])
+            .prologue(b"pro")
+            .build_initiator()
+            .unwrap();
+        let mut resp = HybridHandshake::<Kyber1024>::resp([2u8; 32], [3u8; 32], [5u8; 32], b"pro");
+
+        let len = init.write_message(b"one", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"one");
+        let len = resp.write_message(b"two", &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..len], b"two");
+        let len = init.write_message(b"three", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"three");
+        assert_eq!(resp.handshake_hash().unwrap(), init.get_handshake_hash());
+        assert_eq!(resp.remote_key().unwrap(), [1u8; 32].public_key());
+
+        let mut init = init.into_transport_mode().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"hello", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"hello");
+    }
+}
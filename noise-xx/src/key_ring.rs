@@ -0,0 +1,141 @@
+use crate::StaticKey;
+
+// Length of the public key prefix an initiator sends in message 1 to say
+// which of the responder's keys it expects.
+pub const KEY_HINT_LEN: usize = 8;
+
+// The current static key and, while rotating, the next one. Until every peer
+// has acknowledged the next key both are valid: a responder answers with
+// the key an initiator asks for with a hint, see `select`.
+pub struct KeyRing<K: StaticKey + Clone> {
+    current: K,
+    next: Option<K>,
+}
+
+impl<K: StaticKey + Clone> KeyRing<K> {
+    pub fn new(current: K) -> Self {
+        Self {
+            current,
+            next: None,
+        }
+    }
+    pub fn current(&self) -> &K {
+        &self.current
+    }
+    pub fn next(&self) -> Option<&K> {
+        self.next.as_ref()
+    }
+    // Starts a rotation, replacing any next key not yet promoted.
+    pub fn set_next(&mut self, next: K) {
+        self.next = Some(next);
+    }
+    // Ends a rotation, dropping the current key.
+    pub fn promote(&mut self) -> bool {
+        match self.next.take() {
+            Some(next) => {
+                self.current = next;
+                true
+            }
+            None => false,
+        }
+    }
+    // The key whose public key starts with `hint`, the current one if none
+    // does.
+    pub fn select(&self, hint: &[u8]) -> K {
+        match &self.next {
+            Some(next) if !hint.is_empty() && next.public_key().starts_with(hint) => next.clone(),
+            _ => self.current.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    #[test]
+    fn test_rotation_records() {
+        let mut ring = KeyRing::new([1u8; 32]);
+        let (device, gateway) = transport_pair();
+        let config = RecordConfig::default();
+        let mut device = RecordTransport::new(device, config, 0);
+        let mut gateway = RecordTransport::new(gateway, config, 0);
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        ring.set_next([9u8; 32]);
+        let next = PublicKey(ring.next().unwrap().public_key());
+        let len = device.write_rotate(&next, &mut message, 1).unwrap();
+        let announced = match gateway.read_message(&message[..len], &mut payload, 1) {
+            Ok(Record::Rotate(key)) => key,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(announced, next);
+        assert_eq!(gateway.transport().remote_key(), [1u8; 32].public_key());
+
+        // the gateway trusts the announced key in place of the old one
+        #[cfg(feature = "std")]
+        {
+            let path =
+                std::env::temp_dir().join(std::format!("noise-xx-rotate-{}", std::process::id()));
+            let mut peers = KnownPeers::open(&path).unwrap();
+            let old = gateway.transport().remote_key();
+            peers.check("device", &old).unwrap();
+            peers.rotate("device", &old, announced).unwrap();
+
+            for (s, accepted) in [([9u8; 32], true), ([1u8; 32], false)] {
+                let mut init = Handshake::init([0u8; 32], s, &[]);
+                let (_, mut resp) = handshake_pair();
+                exchange(&mut init, &mut resp);
+                exchange(&mut resp, &mut init);
+                let len = init.write_message(&[], &mut message).unwrap();
+                let result = resp.read_message_known_peer(
+                    &message[..len],
+                    &mut payload,
+                    &mut peers,
+                    "device",
+                );
+                assert_eq!(result.is_ok(), accepted);
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let len = gateway
+            .write_rotate_ack(&announced, &mut message, 2)
+            .unwrap();
+        assert_eq!(
+            device
+                .read_message(&message[..len], &mut payload, 2)
+                .unwrap(),
+            Record::RotateAck(next)
+        );
+        assert!(ring.promote());
+        assert_eq!(next, ring.current().public_key());
+        assert!(!ring.promote());
+    }
+
+    #[test]
+    fn test_key_ring_responder() {
+        let mut ring = KeyRing::new([3u8; 32]);
+        ring.set_next([4u8; 32]);
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+
+        // an initiator that knows the next key asks for it, one that does
+        // not gets the current key
+        for (hint, expected) in [
+            (&[4u8; 32].public_key()[..KEY_HINT_LEN], [4u8; 32]),
+            (&[][..], [3u8; 32]),
+        ] {
+            let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+            let mut resp = DeferredResponder::new([2u8; 32], &[]);
+            let len = init.write_message(hint, &mut buf_init).unwrap();
+            let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+            let mut resp = resp.select(ring.select(&buf_resp[..len])).unwrap();
+            let len = resp.write_message(&[], &mut buf_resp).unwrap();
+            init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+            assert_eq!(init.remote_key().unwrap(), expected.public_key());
+        }
+    }
+}
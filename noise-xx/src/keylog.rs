@@ -0,0 +1,460 @@
+// Logs session secrets for debugging captured traffic, like SSLKEYLOGFILE.
+// Anyone with the log can decrypt the sessions in it, so this is only
+// compiled with the `keylog` feature and only active once a hook is set.
+//
+// Sessions are identified by the initiator's ephemeral public key, the first
+// 32 bytes of message 1. The hook gets one of the labels below, the session
+// and a secret.
+pub type KeyLogFn = fn(label: &str, session: &[u8; 32], secret: &[u8; 32]);
+
+// cipher key of the message 2 and message 3 payloads
+pub const KEYLOG_MSG2_KEY: &str = "MSG2_KEY";
+pub const KEYLOG_MSG3_KEY: &str = "MSG3_KEY";
+pub const KEYLOG_HANDSHAKE_HASH: &str = "HANDSHAKE_HASH";
+// transport keys, initiator to responder and back
+pub const KEYLOG_INITIATOR_KEY: &str = "INITIATOR_KEY";
+pub const KEYLOG_RESPONDER_KEY: &str = "RESPONDER_KEY";
+
+#[cfg(feature = "std")]
+pub use decode::*;
+
+#[cfg(feature = "std")]
+mod decode {
+    use std::collections::HashMap;
+    use std::fmt::Write as _;
+    use std::fs::OpenOptions;
+    use std::io::Write as _;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        cipher_state::TAG_LEN, handshake::DH_LEN, CipherState, Error, Padding, SymmetricState,
+    };
+
+    fn hex(bytes: &[u8]) -> String {
+        let mut s = String::new();
+        for b in bytes {
+            write!(s, "{:02x}", b).unwrap();
+        }
+        s
+    }
+
+    fn parse_hex(s: &str) -> Option<[u8; 32]> {
+        if s.len() != 64 {
+            return None;
+        }
+        let mut out = [0u8; 32];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        Some(out)
+    }
+
+    // One line of the key log: `<label> <hex session> <hex secret>`.
+    pub fn keylog_line(label: &str, session: &[u8; 32], secret: &[u8; 32]) -> String {
+        std::format!("{} {} {}\n", label, hex(session), hex(secret))
+    }
+
+    // A `KeyLogFn` appending to the file named by NOISE_KEYLOGFILE, if set.
+    pub fn keylog_to_env(label: &str, session: &[u8; 32], secret: &[u8; 32]) {
+        let path = match std::env::var_os("NOISE_KEYLOGFILE") {
+            Some(path) => path,
+            None => return,
+        };
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = file.write_all(keylog_line(label, session, secret).as_bytes());
+        }
+    }
+
+    #[derive(Clone, Copy, Default)]
+    struct Secrets {
+        msg2: Option<[u8; 32]>,
+        msg3: Option<[u8; 32]>,
+        initiator: Option<[u8; 32]>,
+        responder: Option<[u8; 32]>,
+    }
+
+    // Parsed key log. Unknown labels and malformed lines are skipped.
+    pub struct KeyLog {
+        sessions: HashMap<[u8; 32], Secrets>,
+    }
+
+    impl KeyLog {
+        pub fn parse(log: &str) -> Self {
+            let mut sessions: HashMap<[u8; 32], Secrets> = HashMap::new();
+            for line in log.lines() {
+                let mut fields = line.split_whitespace();
+                let (label, session, secret) = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(l), Some(s), Some(k)) => (l, s, k),
+                    _ => continue,
+                };
+                let (session, secret) = match (parse_hex(session), parse_hex(secret)) {
+                    (Some(s), Some(k)) => (s, k),
+                    _ => continue,
+                };
+                let entry = sessions.entry(session).or_default();
+                match label {
+                    KEYLOG_MSG2_KEY => entry.msg2 = Some(secret),
+                    KEYLOG_MSG3_KEY => entry.msg3 = Some(secret),
+                    KEYLOG_INITIATOR_KEY => entry.initiator = Some(secret),
+                    KEYLOG_RESPONDER_KEY => entry.responder = Some(secret),
+                    _ => {}
+                }
+            }
+            Self { sessions }
+        }
+    }
+
+    // Messages of a dump file: each is a u32 LE length and the bytes.
+    pub fn parse_dump(dump: &[u8]) -> Result<Vec<&[u8]>, Error> {
+        let mut messages = Vec::new();
+        let mut rest = dump;
+        while !rest.is_empty() {
+            let len = rest
+                .get(..4)
+                .map(|l| u32::from_le_bytes(l.try_into().unwrap()) as usize)
+                .ok_or(Error::Input)?;
+            messages.push(rest.get(4..4 + len).ok_or(Error::Input)?);
+            rest = &rest[4 + len..];
+        }
+        Ok(messages)
+    }
+
+    // UDP payloads of a classic pcap file with Ethernet or raw IPv4 frames.
+    // Other packets are skipped.
+    pub fn parse_pcap(pcap: &[u8]) -> Result<Vec<&[u8]>, Error> {
+        let header = pcap.get(..24).ok_or(Error::Input)?;
+        let u32_at = |b: &[u8], le: bool| {
+            let b: [u8; 4] = b.try_into().unwrap();
+            if le {
+                u32::from_le_bytes(b)
+            } else {
+                u32::from_be_bytes(b)
+            }
+        };
+        let le = match header[..4] {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => true,
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => false,
+            _ => return Err(Error::Input),
+        };
+        let link = u32_at(&header[20..24], le);
+        let mut payloads = Vec::new();
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let record = rest.get(..16).ok_or(Error::Input)?;
+            let len = u32_at(&record[8..12], le) as usize;
+            let frame = rest.get(16..16 + len).ok_or(Error::Input)?;
+            rest = &rest[16 + len..];
+            let ip = match link {
+                // Ethernet, IPv4 ethertype
+                1 if frame.get(12..14) == Some(&[0x08, 0x00]) => &frame[14..],
+                // raw IP
+                101 | 228 => frame,
+                _ => continue,
+            };
+            let ihl = match ip.first() {
+                Some(b) if b >> 4 == 4 => (b & 0x0f) as usize * 4,
+                _ => continue,
+            };
+            if ip.get(9) != Some(&17) {
+                continue;
+            }
+            let total = ip
+                .get(2..4)
+                .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+                .unwrap_or(0);
+            if let Some(udp) = ip.get(ihl..total.min(ip.len())) {
+                if udp.len() >= 8 {
+                    payloads.push(&udp[8..]);
+                }
+            }
+        }
+        Ok(payloads)
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum Decoded {
+        // handshake message 1 to 3 and its payload
+        Handshake {
+            session: [u8; 32],
+            message: u8,
+            payload: Vec<u8>,
+        },
+        Transport {
+            session: [u8; 32],
+            from_initiator: bool,
+            nonce: u64,
+            payload: Vec<u8>,
+        },
+        // no session in the key log could decrypt it
+        Unknown,
+    }
+
+    struct Session {
+        id: [u8; 32],
+        secrets: Secrets,
+        padding: Padding,
+        // h so far, for the payload AD
+        sym: SymmetricState,
+        // next handshake message expected, 4 once in transport
+        step: u8,
+        nonces: [u64; 2],
+    }
+
+    fn decrypt(key: [u8; 32], nonce: u64, ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let mut cipher = CipherState::new(key);
+        cipher.set_nonce(nonce);
+        let mut out = std::vec![0u8; ciphertext.len().checked_sub(TAG_LEN)?];
+        cipher.decrypt_with_ad(ad, ciphertext, &mut out).ok()?;
+        Some(out)
+    }
+
+    fn unpad(padding: Padding, mut payload: Vec<u8>) -> Option<Vec<u8>> {
+        let len = padding.unpad(&payload, payload.len()).ok()?;
+        payload.truncate(len);
+        Some(payload)
+    }
+
+    impl Session {
+        fn decode(&mut self, message: &[u8]) -> Option<Decoded> {
+            let (payload, message_number) = match self.step {
+                2 if message.len() >= 2 * DH_LEN + 2 * TAG_LEN => {
+                    let mut sym = self.sym.clone();
+                    sym.mix_hash(&message[..DH_LEN]);
+                    sym.mix_hash(&message[DH_LEN..2 * DH_LEN + TAG_LEN]);
+                    let ciphertext = &message[2 * DH_LEN + TAG_LEN..];
+                    let payload =
+                        decrypt(self.secrets.msg2?, 0, &sym.handshake_hash(), ciphertext)?;
+                    sym.mix_hash(ciphertext);
+                    self.sym = sym;
+                    (payload, 2)
+                }
+                3 if message.len() >= DH_LEN + 2 * TAG_LEN => {
+                    let mut sym = self.sym.clone();
+                    sym.mix_hash(&message[..DH_LEN + TAG_LEN]);
+                    let ciphertext = &message[DH_LEN + TAG_LEN..];
+                    let payload =
+                        decrypt(self.secrets.msg3?, 0, &sym.handshake_hash(), ciphertext)?;
+                    (payload, 3)
+                }
+                4 => {
+                    let keys = [self.secrets.initiator, self.secrets.responder];
+                    for (i, key) in keys.iter().enumerate() {
+                        let nonce = self.nonces[i];
+                        let payload = key
+                            .and_then(|k| decrypt(k, nonce, &[], message))
+                            .and_then(|p| unpad(self.padding, p));
+                        if let Some(payload) = payload {
+                            self.nonces[i] += 1;
+                            return Some(Decoded::Transport {
+                                session: self.id,
+                                from_initiator: i == 0,
+                                nonce,
+                                payload,
+                            });
+                        }
+                    }
+                    return None;
+                }
+                _ => return None,
+            };
+            self.step += 1;
+            Some(Decoded::Handshake {
+                session: self.id,
+                message: message_number,
+                payload: unpad(self.padding, payload)?,
+            })
+        }
+    }
+
+    // Decrypts the messages of the sessions in a key log, in capture order.
+    // Transport messages must arrive in nonce order per direction.
+    pub struct Decoder {
+        log: KeyLog,
+        prologue: Vec<u8>,
+        padding: Padding,
+        sessions: Vec<Session>,
+    }
+
+    impl Decoder {
+        pub fn new(log: KeyLog, prologue: &[u8]) -> Self {
+            Self {
+                log,
+                prologue: prologue.to_vec(),
+                padding: Padding::None,
+                sessions: Vec::new(),
+            }
+        }
+        // Strips padding from payloads of sessions seen from now on. Only
+        // whether the peers padded matters, not the policy.
+        pub fn set_padding(&mut self, padding: Padding) {
+            self.padding = padding;
+        }
+        pub fn decode(&mut self, message: &[u8]) -> Decoded {
+            for session in self.sessions.iter_mut() {
+                if let Some(decoded) = session.decode(message) {
+                    return decoded;
+                }
+            }
+            // message 1, its payload is not encrypted
+            let id: Option<[u8; 32]> = message.get(..DH_LEN).and_then(|e| e.try_into().ok());
+            let secrets = match id.and_then(|id| self.log.sessions.get(&id)) {
+                Some(secrets) => *secrets,
+                None => return Decoded::Unknown,
+            };
+            let id = id.unwrap();
+            let payload = match unpad(self.padding, message[DH_LEN..].to_vec()) {
+                Some(payload) => payload,
+                None => return Decoded::Unknown,
+            };
+            let mut sym = SymmetricState::new();
+            sym.mix_hash(&self.prologue);
+            sym.mix_hash(&id);
+            sym.mix_hash(&message[DH_LEN..]);
+            self.sessions.retain(|s| s.id != id);
+            self.sessions.push(Session {
+                id,
+                secrets,
+                padding: self.padding,
+                sym,
+                step: 2,
+                nonces: [0; 2],
+            });
+            Decoded::Handshake {
+                session: id,
+                message: 1,
+                payload,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use crate::keylog::*;
+    use crate::*;
+    use std::string::String;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    static LOG: Mutex<String> = Mutex::new(String::new());
+
+    fn log_to_string(label: &str, session: &[u8; 32], secret: &[u8; 32]) {
+        LOG.lock()
+            .unwrap()
+            .push_str(&keylog_line(label, session, secret));
+    }
+
+    // A session with initiator ephemeral `e`, logged on the responder side
+    // only, which is enough.
+    fn capture(e: [u8; 32], padding: Padding) -> Vec<Vec<u8>> {
+        let mut buf_init = [0u8; 200];
+        let mut buf_resp = [0u8; 200];
+        let mut capture: Vec<Vec<u8>> = Vec::new();
+        let mut init = Handshake::init(e, [1u8; 32], b"v1");
+        let mut resp = Handshake::resp([2u8; 32], [3u8; 32], b"v1");
+        init.set_padding(padding);
+        resp.set_padding(padding);
+        resp.set_keylog(log_to_string);
+
+        let len = init.write_message(b"one", &mut buf_init).unwrap();
+        capture.push(buf_init[..len].to_vec());
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let len = resp.write_message(b"two", &mut buf_resp).unwrap();
+        capture.push(buf_resp[..len].to_vec());
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let len = init.write_message(b"three", &mut buf_init).unwrap();
+        capture.push(buf_init[..len].to_vec());
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        for (from_init, data) in [(true, &b"up"[..]), (false, b"down"), (true, b"again")] {
+            let (from, to) = if from_init {
+                (&mut init, &mut resp)
+            } else {
+                (&mut resp, &mut init)
+            };
+            let len = from.write_message(data, &mut buf_init).unwrap();
+            capture.push(buf_init[..len].to_vec());
+            to.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        }
+        capture
+    }
+
+    fn expected(e: [u8; 32]) -> [Decoded; 6] {
+        let session = e.public_key();
+        let handshake = |message, payload: &[u8]| Decoded::Handshake {
+            session,
+            message,
+            payload: payload.to_vec(),
+        };
+        let transport = |from_initiator, nonce, payload: &[u8]| Decoded::Transport {
+            session,
+            from_initiator,
+            nonce,
+            payload: payload.to_vec(),
+        };
+        [
+            handshake(1, b"one"),
+            handshake(2, b"two"),
+            handshake(3, b"three"),
+            transport(true, 0, b"up"),
+            transport(false, 0, b"down"),
+            transport(true, 1, b"again"),
+        ]
+    }
+
+    #[test]
+    fn test_keylog_decoder() {
+        let capture = capture([0u8; 32], Padding::None);
+        let mut dump = Vec::new();
+        for message in &capture {
+            dump.extend_from_slice(&(message.len() as u32).to_le_bytes());
+            dump.extend_from_slice(message);
+        }
+        let mut decoder = Decoder::new(KeyLog::parse(&LOG.lock().unwrap()), b"v1");
+        let decoded: Vec<_> = parse_dump(&dump)
+            .unwrap()
+            .iter()
+            .map(|m| decoder.decode(m))
+            .collect();
+        assert_eq!(decoded, expected([0u8; 32]));
+        assert_eq!(decoder.decode(&[0u8; 40]), Decoded::Unknown);
+    }
+
+    #[test]
+    fn test_keylog_decoder_padded() {
+        let capture = capture([4u8; 32], Padding::Block(16));
+        let mut decoder = Decoder::new(KeyLog::parse(&LOG.lock().unwrap()), b"v1");
+        decoder.set_padding(Padding::Block(16));
+        let decoded: Vec<_> = capture.iter().map(|m| decoder.decode(m)).collect();
+        assert_eq!(decoded, expected([4u8; 32]));
+    }
+
+    #[test]
+    fn test_parse_pcap() {
+        let mut pcap = std::vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend_from_slice(&[0u8; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&1u32.to_le_bytes());
+        for payload in [&b"first"[..], b"second"] {
+            let mut frame = std::vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            let total = (20 + 8 + payload.len()) as u16;
+            let mut ip = std::vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+            ip[2..4].copy_from_slice(&total.to_be_bytes());
+            ip.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+            frame.extend_from_slice(&ip);
+            frame.extend_from_slice(&[0x1f, 0x90, 0x1f, 0x90]);
+            frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(payload);
+            pcap.extend_from_slice(&[0u8; 8]);
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&frame);
+        }
+        assert_eq!(parse_pcap(&pcap).unwrap(), [&b"first"[..], b"second"]);
+    }
+}
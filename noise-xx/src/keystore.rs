@@ -0,0 +1,323 @@
+use core::fmt;
+use std::fs;
+use std::path::Path;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::{CipherState, Error, Handshake, StaticKey};
+
+const MAGIC: &[u8; 4] = b"NXKS";
+pub const KEYSTORE_VERSION: u8 = 1;
+pub const KEYSTORE_SALT_LEN: usize = 16;
+// magic, version, m_cost, t_cost, p_cost, salt
+const HEADER_LEN: usize = 4 + 1 + 3 * 4 + KEYSTORE_SALT_LEN;
+const TAG_LEN: usize = crate::cipher_state::TAG_LEN;
+// refuse files that would make loading take gigabytes of RAM or minutes
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+// Argon2id cost, stored in the file header so it can be raised later
+// without breaking old files. m_cost is in KiB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+// OWASP's minimum for Argon2id
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeyEntry {
+    pub label: String,
+    // seconds since the Unix epoch, as given to `insert`
+    pub created: u64,
+    pub secret: [u8; 32],
+}
+
+impl KeyEntry {
+    pub fn public_key(&self) -> [u8; 32] {
+        self.secret.public_key()
+    }
+}
+
+// the secret stays out of logs
+impl fmt::Debug for KeyEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyEntry")
+            .field("label", &self.label)
+            .field("created", &self.created)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+// Static key pairs encrypted under a passphrase:
+//
+//  "NXKS" | version | m_cost | t_cost | p_cost | salt | ChaCha20-Poly1305(entries)
+//
+// The key is Argon2id(passphrase, salt) and the nonce 0: every save takes a
+// fresh salt, so a key never encrypts twice. The header is the AD. Entries
+// are a u16 count, then per entry a u8 label length, the label, u64 created
+// and the 32 byte secret. All integers are little endian.
+#[derive(Default)]
+pub struct KeyStore {
+    entries: Vec<KeyEntry>,
+    params: KdfParams,
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: KdfParams) -> Result<[u8; 32], Error> {
+    if params.m_cost > MAX_M_COST || params.t_cost > MAX_T_COST || params.p_cost > MAX_P_COST {
+        return Err(Error::Input);
+    }
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|_| Error::Input)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| Error::Input)?;
+    Ok(key)
+}
+
+impl KeyStore {
+    pub fn new(params: KdfParams) -> Self {
+        Self {
+            entries: Vec::new(),
+            params,
+        }
+    }
+    pub fn entries(&self) -> &[KeyEntry] {
+        &self.entries
+    }
+    pub fn get(&self, label: &str) -> Option<&KeyEntry> {
+        self.entries.iter().find(|e| e.label == label)
+    }
+    // Labels are unique, non-empty and at most 255 bytes.
+    pub fn insert(&mut self, label: &str, secret: [u8; 32], created: u64) -> Result<(), Error> {
+        if label.is_empty() || label.len() > 255 || self.get(label).is_some() {
+            return Err(Error::Input);
+        }
+        if self.entries.len() == u16::MAX as usize {
+            return Err(Error::Input);
+        }
+        self.entries.push(KeyEntry {
+            label: label.to_string(),
+            created,
+            secret,
+        });
+        Ok(())
+    }
+    pub fn remove(&mut self, label: &str) -> Option<KeyEntry> {
+        let i = self.entries.iter().position(|e| e.label == label)?;
+        Some(self.entries.remove(i))
+    }
+    // Replaces the key under `label`. The old one is kept as `<label>.old`,
+    // replacing an earlier one, until peers have moved over, see `KeyRing`.
+    pub fn rotate(&mut self, label: &str, secret: [u8; 32], created: u64) -> Result<(), Error> {
+        let old_label = std::format!("{}.old", label);
+        if old_label.len() > 255 {
+            return Err(Error::Input);
+        }
+        let mut old = self.remove(label).ok_or(Error::Input)?;
+        self.remove(&old_label);
+        old.label = old_label;
+        self.entries.push(old);
+        self.insert(label, secret, created)
+    }
+    pub fn handshake(
+        &self,
+        label: &str,
+        init: bool,
+        e: [u8; 32],
+        prologue: &[u8],
+    ) -> Result<Handshake, Error> {
+        let entry = self.get(label).ok_or(Error::Input)?;
+        Ok(Handshake::new(init, e, entry.secret, prologue))
+    }
+
+    // `salt` must be random and not used for an earlier save.
+    pub fn encrypt(
+        &self,
+        passphrase: &[u8],
+        salt: [u8; KEYSTORE_SALT_LEN],
+    ) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 2 + self.entries.len() * 300 + TAG_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(KEYSTORE_VERSION);
+        for cost in [self.params.m_cost, self.params.t_cost, self.params.p_cost] {
+            out.extend_from_slice(&cost.to_le_bytes());
+        }
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        for entry in &self.entries {
+            out.push(entry.label.len() as u8);
+            out.extend_from_slice(entry.label.as_bytes());
+            out.extend_from_slice(&entry.created.to_le_bytes());
+            out.extend_from_slice(&entry.secret);
+        }
+        let key = derive_key(passphrase, &salt, self.params)?;
+        let len = out.len() - HEADER_LEN;
+        out.resize(out.len() + TAG_LEN, 0);
+        let (header, body) = out.split_at_mut(HEADER_LEN);
+        CipherState::new(key).encrypt_in_place_with_ad(header, body, len)?;
+        Ok(out)
+    }
+    // A wrong passphrase or a modified file fails with `Error::Decrypt`.
+    pub fn decrypt(data: &[u8], passphrase: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN + TAG_LEN || &data[..4] != MAGIC {
+            return Err(Error::Input);
+        }
+        if data[4] != KEYSTORE_VERSION {
+            return Err(Error::Input);
+        }
+        let (header, body) = data.split_at(HEADER_LEN);
+        let cost = |i: usize| u32::from_le_bytes(header[5 + 4 * i..9 + 4 * i].try_into().unwrap());
+        let params = KdfParams {
+            m_cost: cost(0),
+            t_cost: cost(1),
+            p_cost: cost(2),
+        };
+        let key = derive_key(
+            passphrase,
+            &header[HEADER_LEN - KEYSTORE_SALT_LEN..],
+            params,
+        )?;
+        let mut plain = std::vec![0u8; body.len() - TAG_LEN];
+        CipherState::new(key).decrypt_with_ad(header, body, &mut plain)?;
+
+        let mut store = Self::new(params);
+        let mut rest = &plain[..];
+        let mut take = |n: usize| -> Result<&[u8], Error> {
+            let (head, tail) = rest.split_at_checked(n).ok_or(Error::Input)?;
+            rest = tail;
+            Ok(head)
+        };
+        let count = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        for _ in 0..count {
+            let label_len = take(1)?[0] as usize;
+            let label = core::str::from_utf8(take(label_len)?).map_err(|_| Error::Input)?;
+            let label = label.to_string();
+            let created = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            let secret = take(32)?.try_into().unwrap();
+            store.insert(&label, secret, created)?;
+        }
+        Ok(store)
+    }
+    pub fn load<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Self, Error> {
+        Self::decrypt(&fs::read(path)?, passphrase)
+    }
+    // Replaces the file atomically.
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+        salt: [u8; KEYSTORE_SALT_LEN],
+    ) -> Result<(), Error> {
+        let data = self.encrypt(passphrase, salt)?;
+        let mut tmp = path.as_ref().to_path_buf().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+    use std::env;
+    use std::fs;
+
+    // cheap enough for debug builds
+    const PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_keystore_round_trip() {
+        let mut store = KeyStore::new(PARAMS);
+        store.insert("gateway", [1u8; 32], 1_700_000_000).unwrap();
+        store.insert("backup", [2u8; 32], 1_700_000_100).unwrap();
+        assert!(matches!(
+            store.insert("gateway", [3u8; 32], 0),
+            Err(Error::Input)
+        ));
+        store.rotate("gateway", [3u8; 32], 1_800_000_000).unwrap();
+
+        let data = store.encrypt(b"correct horse", [7u8; 16]).unwrap();
+        let loaded = KeyStore::decrypt(&data, b"correct horse").unwrap();
+        assert_eq!(loaded.entries(), store.entries());
+        let gateway = loaded.get("gateway").unwrap();
+        assert_eq!(gateway.secret, [3u8; 32]);
+        assert_eq!(gateway.created, 1_800_000_000);
+        assert_eq!(loaded.get("gateway.old").unwrap().secret, [1u8; 32]);
+
+        assert!(matches!(
+            KeyStore::decrypt(&data, b"wrong"),
+            Err(Error::Decrypt)
+        ));
+        // the header is authenticated too
+        let mut tampered = data.clone();
+        tampered[6] ^= 1;
+        assert!(KeyStore::decrypt(&tampered, b"correct horse").is_err());
+        let mut tampered = data;
+        tampered[4] = 2;
+        assert!(matches!(
+            KeyStore::decrypt(&tampered, b"correct horse"),
+            Err(Error::Input)
+        ));
+    }
+
+    #[test]
+    fn test_keystore_limits() {
+        let mut store = KeyStore::new(PARAMS);
+        store.insert("gateway", [0xab; 32], 0).unwrap();
+        let debug = std::format!("{:?}", store.get("gateway").unwrap());
+        assert!(debug.contains("<redacted>") && !debug.contains("171"));
+
+        // a crafted header must not make loading run for ages
+        let data = store.encrypt(b"pw", [7u8; 16]).unwrap();
+        for offset in [9, 13] {
+            let mut crafted = data.clone();
+            crafted[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(matches!(
+                KeyStore::decrypt(&crafted, b"pw"),
+                Err(Error::Input)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_keystore_handshake() {
+        let path = env::temp_dir().join(std::format!("noise-xx-keystore-{}", std::process::id()));
+        let mut store = KeyStore::new(PARAMS);
+        store.insert("device", [1u8; 32], 0).unwrap();
+        store.save(&path, b"pw", [9u8; 16]).unwrap();
+        let store = KeyStore::load(&path, b"pw").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut init = store.handshake("device", true, [0u8; 32], &[]).unwrap();
+        let (_, mut resp) = handshake_pair();
+        complete(&mut init, &mut resp);
+        assert_eq!(
+            resp.remote_key().unwrap(),
+            store.get("device").unwrap().public_key()
+        );
+        assert!(store.handshake("missing", true, [0u8; 32], &[]).is_err());
+    }
+}
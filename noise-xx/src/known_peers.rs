@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::{String, ToString};
+
+use crate::{Error, Handshake, PublicKey, StaticKey};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    // recorded on first use
+    Trusted,
+    // set explicitly by an operator
+    Pinned,
+    Revoked,
+}
+
+impl PeerStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trusted => "trusted",
+            Self::Pinned => "pinned",
+            Self::Revoked => "revoked",
+        }
+    }
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "trusted" => Some(Self::Trusted),
+            "pinned" => Some(Self::Pinned),
+            "revoked" => Some(Self::Revoked),
+            _ => None,
+        }
+    }
+}
+
+// Trust-on-first-use store of remote static keys, one peer per line:
+//
+//  <name> <hex key> <trusted|pinned|revoked>
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: BTreeMap<String, (PublicKey, PeerStatus)>,
+}
+
+impl KnownPeers {
+    // A missing file is treated as an empty store.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut peers = BTreeMap::new();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (name, key, status) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(key), Some(status)) => (name, key, status),
+                _ => return Err(Error::Input),
+            };
+            let key = PublicKey::from_hex(key)?;
+            let status = PeerStatus::parse(status).ok_or(Error::Input)?;
+            peers.insert(name.to_string(), (key, status));
+        }
+        Ok(Self { path, peers })
+    }
+    fn save(&self) -> Result<(), Error> {
+        let mut contents = String::new();
+        for (name, (key, status)) in &self.peers {
+            contents.push_str(name);
+            contents.push(' ');
+            for b in key.as_bytes() {
+                write!(contents, "{:02x}", b).unwrap();
+            }
+            contents.push(' ');
+            contents.push_str(status.as_str());
+            contents.push('\n');
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+    fn insert(&mut self, name: &str, key: PublicKey, status: PeerStatus) -> Result<(), Error> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::Input);
+        }
+        // if the store cannot be written the peer is not recorded either
+        let previous = self.peers.insert(name.to_string(), (key, status));
+        self.save().inspect_err(|_| match previous {
+            Some(previous) => {
+                self.peers.insert(name.to_string(), previous);
+            }
+            None => {
+                self.peers.remove(name);
+            }
+        })
+    }
+    // Accepts and records an unknown peer, accepts a matching key and rejects
+    // a changed or revoked one.
+    pub fn check(&mut self, name: &str, key: &PublicKey) -> Result<(), Error> {
+        match self.peers.get(name) {
+            None => self.insert(name, *key, PeerStatus::Trusted),
+            Some((_, PeerStatus::Revoked)) => Err(Error::Revoked),
+            Some((known, _)) if known == key => Ok(()),
+            Some(_) => Err(Error::KeyChanged),
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<(PublicKey, PeerStatus)> {
+        self.peers.get(name).copied()
+    }
+    pub fn pin(&mut self, name: &str, key: PublicKey) -> Result<(), Error> {
+        self.insert(name, key, PeerStatus::Pinned)
+    }
+    pub fn revoke(&mut self, name: &str) -> Result<(), Error> {
+        let key = self.peers.get(name).ok_or(Error::Input)?.0;
+        self.insert(name, key, PeerStatus::Revoked)
+    }
+    // Replaces `old` with `new`, as announced by the peer in a rotate record
+    // of a session authenticated with `old`. The status is kept, and if the
+    // store cannot be written the old key stays in effect.
+    pub fn rotate(&mut self, name: &str, old: &PublicKey, new: PublicKey) -> Result<(), Error> {
+        let entry = self.peers.get_mut(name).ok_or(Error::Input)?;
+        match *entry {
+            (_, PeerStatus::Revoked) => return Err(Error::Revoked),
+            (known, _) if known != *old => return Err(Error::KeyChanged),
+            _ => {}
+        }
+        entry.0 = new;
+        self.save().inspect_err(|_| {
+            if let Some(entry) = self.peers.get_mut(name) {
+                entry.0 = *old;
+            }
+        })
+    }
+    // If the store cannot be written the peer is kept.
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        let previous = self.peers.remove(name).ok_or(Error::Input)?;
+        self.save().inspect_err(|_| {
+            self.peers.insert(name.to_string(), previous);
+        })
+    }
+    pub fn list(&self) -> impl Iterator<Item = (&str, PublicKey, PeerStatus)> {
+        self.peers
+            .iter()
+            .map(|(name, (key, status))| (name.as_str(), *key, *status))
+    }
+}
+
+impl<K: StaticKey> Handshake<K> {
+    // Reads message 2 (initiator) or 3 (responder) and checks the received
+    // remote static key against the store. A rejected key poisons the
+    // handshake, so it never gets as far as message 3.
+    pub fn read_message_known_peer(
+        &mut self,
+        message: &[u8],
+        payload: &mut [u8],
+        peers: &mut KnownPeers,
+        name: &str,
+    ) -> Result<usize, Error> {
+        let len = self.read_message(message, payload)?;
+        let rs = self.remote_key().ok_or(Error::NotMyTurn)?;
+        if let Err(e) = peers.check(name, &rs) {
+            self.poison();
+            return Err(e);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+    use std::fs;
+
+    fn handshake_to_msg2(resp_s: [u8; 32]) -> (Handshake, [u8; 100], usize) {
+        let (mut init, _) = handshake_pair();
+        let mut resp = Handshake::resp([2u8; 32], resp_s, &[]);
+        exchange(&mut init, &mut resp);
+        let mut msg2 = [0u8; 100];
+        let len = resp.write_message(&[], &mut msg2).unwrap();
+        (init, msg2, len)
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let path = std::env::temp_dir().join(std::format!("known_peers_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut peers = KnownPeers::open(&path).unwrap();
+        let mut payload = [0u8; 100];
+
+        let (mut init, msg, len) = handshake_to_msg2([3u8; 32]);
+        init.read_message_known_peer(&msg[..len], &mut payload, &mut peers, "gw")
+            .unwrap();
+        let key = init.remote_key().unwrap();
+
+        let mut peers = KnownPeers::open(&path).unwrap();
+        assert_eq!(peers.get("gw"), Some((key, PeerStatus::Trusted)));
+
+        let (mut init, msg, len) = handshake_to_msg2([4u8; 32]);
+        assert!(matches!(
+            init.read_message_known_peer(&msg[..len], &mut payload, &mut peers, "gw"),
+            Err(Error::KeyChanged)
+        ));
+        assert!(matches!(
+            init.write_message(&[], &mut payload),
+            Err(Error::Poisoned)
+        ));
+
+        // nothing is recorded when the store cannot be written
+        let missing_dir = std::env::temp_dir().join("noise-xx-missing-dir/known_peers");
+        let mut unwritable = KnownPeers::open(missing_dir).unwrap();
+        assert!(unwritable.check("new", &key).is_err());
+        assert_eq!(unwritable.get("new"), None);
+
+        peers.pin("gw", PublicKey([7u8; 32])).unwrap();
+        peers.pin("other", key).unwrap();
+        assert!(matches!(peers.check("gw", &key), Err(Error::KeyChanged)));
+        peers.check("other", &key).unwrap();
+        peers.revoke("other").unwrap();
+        assert!(matches!(peers.check("other", &key), Err(Error::Revoked)));
+
+        // rotation needs the current key and keeps the status
+        assert!(matches!(
+            peers.rotate("gw", &key, PublicKey([8u8; 32])),
+            Err(Error::KeyChanged)
+        ));
+        assert!(matches!(
+            peers.rotate("other", &key, PublicKey([8u8; 32])),
+            Err(Error::Revoked)
+        ));
+        peers
+            .rotate("gw", &PublicKey([7u8; 32]), PublicKey([8u8; 32]))
+            .unwrap();
+        peers.check("gw", &PublicKey([8u8; 32])).unwrap();
+
+        // a failed write keeps a removed peer, the temporary file cannot be
+        // created while a directory has its name
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::create_dir(&tmp).unwrap();
+        assert!(peers.remove("other").is_err());
+        assert_eq!(peers.get("other"), Some((key, PeerStatus::Revoked)));
+        fs::remove_dir(&tmp).unwrap();
+
+        let peers = KnownPeers::open(&path).unwrap();
+        let listed: std::vec::Vec<_> = peers.list().collect();
+        assert_eq!(
+            listed,
+            [
+                ("gw", PublicKey([8u8; 32]), PeerStatus::Pinned),
+                ("other", key, PeerStatus::Revoked)
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,27 +1,135 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "cert")]
+mod cert;
 mod cipher_state;
+mod connection;
+mod fragment;
 mod handshake;
+#[cfg(feature = "hfs")]
+mod hfs;
+mod key_ring;
+#[cfg(feature = "keylog")]
+pub mod keylog;
+#[cfg(feature = "keystore")]
+mod keystore;
+#[cfg(feature = "std")]
+mod known_peers;
+#[cfg(feature = "hfs")]
+mod mlkem;
+mod mux;
+mod observer;
+mod padding;
+mod public_key;
+mod record;
+mod reliable;
+mod resumption;
+mod sas;
+#[cfg(feature = "serial")]
+mod serial;
+#[cfg(feature = "std")]
+mod session_manager;
+#[cfg(target_has_atomic = "64")]
+mod shared;
+mod static_key;
 mod symmetric_state;
+#[cfg(test)]
+mod test_util;
 mod transport;
 mod x25519;
 
+#[cfg(feature = "cert")]
+pub use cert::{Certificate, Role, MAX_CERT_LEN, MAX_DEVICE_ID_LEN};
 use cipher_state::CipherState;
-pub use handshake::Handshake;
+pub use connection::{Connection, ConnectionEvent};
+pub use fragment::{Fragments, Reassembler, FRAGMENT_HEADER_LEN, MAX_FRAGMENTS};
+pub use handshake::{DeferredResponder, Handshake};
+#[cfg(feature = "hfs")]
+pub use hfs::{HybridHandshake, Kem};
+pub use key_ring::{KeyRing, KEY_HINT_LEN};
+#[cfg(feature = "keylog")]
+pub use keylog::KeyLogFn;
+#[cfg(feature = "keystore")]
+pub use keystore::{KdfParams, KeyEntry, KeyStore, KEYSTORE_SALT_LEN, KEYSTORE_VERSION};
+#[cfg(feature = "std")]
+pub use known_peers::{KnownPeers, PeerStatus};
+#[cfg(feature = "hfs")]
+pub use mlkem::{
+    MlKem768, HFS_MLKEM768_PROT_NAME, MLKEM768_CIPHERTEXT_LEN, MLKEM768_PUBLIC_KEY_LEN,
+    MLKEM768_SEED_LEN,
+};
+pub use mux::{Mux, MuxEvent, MUX_HEADER_LEN};
+#[cfg(target_has_atomic = "64")]
+pub use observer::Counters;
+#[cfg(feature = "tracing")]
+pub use observer::TracingObserver;
+pub use observer::{Event, Observer};
+pub use padding::Padding;
+pub use public_key::{
+    Fingerprint, PublicKey, BASE64URL_LEN, BECH32_HRP, BECH32_LEN, FINGERPRINT_LEN,
+};
+pub use record::{Record, RecordConfig, RecordTransport, RecordType, Timer};
+pub use reliable::{Received, ReliableHandshake, RetransmitConfig};
+pub use resumption::{
+    Resumption, ResumptionTicket, TicketIssuer, RESUME_MSG1_OVERHEAD, RESUME_MSG2_OVERHEAD,
+    TICKET_LEN,
+};
+pub use sas::{Sas, SasInitiator, SasResponder};
+#[cfg(feature = "serial")]
+pub use serial::{
+    cobs_decode, cobs_encode, crc16, slip_decode, slip_encode, Framing, SerialLink, CRC_LEN,
+    SERIAL_NONCE_LEN,
+};
+#[cfg(feature = "std")]
+pub use session_manager::{
+    SessionEvent, SessionManager, DATA_HEADER_LEN, DEFAULT_MAX_PENDING, FINISH_HEADER_LEN,
+    INIT_HEADER_LEN, MSG_DATA, MSG_FINISH, MSG_INIT, MSG_RESP, RESP_HEADER_LEN,
+};
+#[cfg(target_has_atomic = "64")]
+pub use shared::SharedSender;
+pub use static_key::StaticKey;
 use symmetric_state::SymmetricState;
 pub use transport::{NoiseRead, NoiseWrite, Transport};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     Input,
     Decrypt,
     Dh,
     NotMyTurn,
     NeedUpgrade,
+    Commitment,
+    Certificate,
+    Expired,
+    KeyChanged,
+    Revoked,
+    Timeout,
+    QueueFull,
+    Closed,
+    Truncated,
+    Replay,
+    // the handshake rejected the peer and cannot continue
+    Poisoned,
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+    #[cfg(feature = "serial")]
+    Serial(embedded_io::ErrorKind),
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.kind())
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::test_util::*;
     use crate::*;
     extern crate alloc;
 
@@ -112,4 +220,202 @@ mod test {
         let len = resp.read_message(&buf_init[..len], buf_resp).unwrap();
         assert_eq!(&buf_resp[..len], b"hello");
     }
+
+    #[test]
+    fn test_deferred_responder_using_snow() {
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+        let tenants = [(&b"tenant-a"[..], [3u8; 32]), (b"tenant-b", [4u8; 32])];
+
+        let mut init = snow::Builder::new(PROT_NAME.parse().unwrap())
+            .local_private_key(&[1u8; 32])
+            .build_initiator()
+            .unwrap();
+        let mut resp = DeferredResponder::new([2u8; 32], &[]);
+
+        let len = init.write_message(b"tenant-b", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let (_, s) = tenants
+            .iter()
+            .find(|(id, _)| *id == &buf_resp[..len])
+            .unwrap();
+        let mut resp = resp.select(*s).unwrap();
+
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(
+            init.get_remote_static().unwrap(),
+            x25519_dalek::x25519([4u8; 32], x25519_dalek::X25519_BASEPOINT_BYTES)
+        );
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+
+        let mut init = init.into_transport_mode().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"hello", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"hello");
+
+        let resp = DeferredResponder::new([2u8; 32], &[]);
+        assert!(matches!(resp.select([3u8; 32]), Err(Error::NotMyTurn)));
+    }
+
+    #[test]
+    fn test_transport_with_ad() {
+        let (mut init, mut resp) = transport_pair();
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+
+        let len = init
+            .write_message_with_ad(b"device-1", b"hello", &mut buf_init)
+            .unwrap();
+        let len = resp
+            .read_message_with_ad(b"device-1", &buf_init[..len], &mut buf_resp)
+            .unwrap();
+        assert_eq!(&buf_resp[..len], b"hello");
+
+        let len = init
+            .write_message_with_ad(b"device-1", b"hello", &mut buf_init)
+            .unwrap();
+        assert!(matches!(
+            resp.read_message_with_ad(b"device-2", &buf_init[..len], &mut buf_resp),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn test_vectored_and_batch() {
+        let (mut init, mut resp) = transport_pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        let len = init
+            .write_vectored(&[b"hdr:", b"body"], &mut message)
+            .unwrap();
+        let len = resp.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"hdr:body");
+
+        let mut out = [0u8; 100];
+        let mut lens = [0usize; 3];
+        let total = init
+            .write_batch(&[b"one", b"", b"three"], &mut out, &mut lens)
+            .unwrap();
+        assert_eq!(lens, [3 + 16, 16, 5 + 16]);
+        assert_eq!(total, lens.iter().sum());
+        let mut offset = 0;
+        for (expected, len) in [&b"one"[..], b"", b"three"].iter().zip(lens) {
+            let n = resp
+                .read_message(&out[offset..offset + len], &mut payload)
+                .unwrap();
+            assert_eq!(&payload[..n], *expected);
+            offset += len;
+        }
+        assert!(init
+            .write_batch(&[&b"x"[..]; 2], &mut out, &mut lens[..1])
+            .is_err());
+        // nothing is encrypted unless the whole batch fits
+        let nonce = init.send_nonce();
+        assert!(matches!(
+            init.write_batch(&[&[0u8; 40][..]; 3], &mut out, &mut lens),
+            Err(Error::Input)
+        ));
+        assert_eq!(init.send_nonce(), nonce);
+    }
+
+    #[test]
+    fn test_export_keying_material() {
+        let (init, resp) = transport_pair();
+
+        let mut key_init = [0u8; 32];
+        let mut key_resp = [0u8; 32];
+        init.export_keying_material(b"firmware", b"v1", &mut key_init)
+            .unwrap();
+        resp.export_keying_material(b"firmware", b"v1", &mut key_resp)
+            .unwrap();
+        assert_eq!(key_init, key_resp);
+
+        let (read, write) = resp.split();
+        read.export_keying_material(b"firmware", b"v1", &mut key_resp)
+            .unwrap();
+        assert_eq!(key_init, key_resp);
+        write
+            .export_keying_material(b"firmware", b"v2", &mut key_resp)
+            .unwrap();
+        assert_ne!(key_init, key_resp);
+        init.export_keying_material(b"pairing", b"v1", &mut key_resp)
+            .unwrap();
+        assert_ne!(key_init, key_resp);
+    }
+
+    #[test]
+    fn test_sas() {
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+
+        let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+        let mut resp = Handshake::resp([2u8; 32], [3u8; 32], &[]);
+
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert!(resp.handshake_hash().is_err());
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+
+        let h_init = init.handshake_hash().unwrap();
+        let h_resp = resp.handshake_hash().unwrap();
+        assert_eq!(Sas::new(&h_init).numeric(), Sas::new(&h_resp).numeric());
+        assert!(Sas::new(&h_init).numeric() < 1_000_000);
+        assert_eq!(Sas::new(&h_init).emoji(), Sas::new(&h_resp).emoji());
+
+        let sas_init = SasInitiator::new(h_init, [4u8; 32]);
+        let sas_resp = SasResponder::new(h_resp, [5u8; 32], sas_init.commitment());
+        let (nonce_i, code_init) = sas_init.reveal(&sas_resp.nonce());
+        let code_resp = sas_resp.verify(&nonce_i).unwrap();
+        assert_eq!(code_init.numeric(), code_resp.numeric());
+        assert_eq!(code_init.words(), code_resp.words());
+        assert_ne!(code_init.numeric(), Sas::new(&h_init).numeric());
+
+        let sas_init = SasInitiator::new(h_init, [4u8; 32]);
+        let sas_resp = SasResponder::new(h_resp, [5u8; 32], sas_init.commitment());
+        assert!(matches!(
+            sas_resp.verify(&[6u8; 32]),
+            Err(Error::Commitment)
+        ));
+    }
+
+    #[test]
+    fn test_rekey_using_snow() {
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+
+        let mut resp = snow::Builder::new(PROT_NAME.parse().unwrap())
+            .local_private_key(&[3u8; 32])
+            .build_responder()
+            .unwrap();
+        let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+
+        let mut resp = resp.into_transport_mode().unwrap();
+        let mut init = init.upgrade().unwrap();
+
+        init.rekey_send();
+        resp.rekey_incoming();
+        let len = init.write_message(b"rekeyed", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"rekeyed");
+
+        resp.rekey_outgoing();
+        init.rekey_recv();
+        let len = resp.write_message(b"back", &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..len], b"back");
+    }
 }
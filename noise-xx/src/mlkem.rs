@@ -0,0 +1,75 @@
+use ml_kem::{Decapsulate, DecapsulationKey768, EncapsulationKey768, KeyExport};
+
+use crate::{Error, Kem};
+
+pub const MLKEM768_PUBLIC_KEY_LEN: usize = 1184;
+pub const MLKEM768_CIPHERTEXT_LEN: usize = 1088;
+pub const MLKEM768_SEED_LEN: usize = 64;
+pub const HFS_MLKEM768_PROT_NAME: &str = "Noise_XXhfs_25519+MLKEM768_ChaChaPoly_BLAKE2s";
+
+// ML-KEM-768 (FIPS 203) from the RustCrypto `ml-kem` crate, whose
+// arithmetic on secret values is constant time.
+pub struct MlKem768(DecapsulationKey768);
+
+impl MlKem768 {
+    // The key pair from a fresh random seed, d || z in FIPS 203.
+    pub fn new(seed: [u8; MLKEM768_SEED_LEN]) -> Self {
+        Self(DecapsulationKey768::from_seed(seed.into()))
+    }
+}
+
+impl Kem for MlKem768 {
+    const PROT_NAME: &'static str = HFS_MLKEM768_PROT_NAME;
+    const PUBLIC_KEY_LEN: usize = MLKEM768_PUBLIC_KEY_LEN;
+    const CIPHERTEXT_LEN: usize = MLKEM768_CIPHERTEXT_LEN;
+    type PublicKey = [u8; MLKEM768_PUBLIC_KEY_LEN];
+    type Ciphertext = [u8; MLKEM768_CIPHERTEXT_LEN];
+    fn public_key(&self) -> Self::PublicKey {
+        self.0.encapsulation_key().to_bytes().into()
+    }
+    // Fails with `Error::Input` if the key does not pass the FIPS 203
+    // modulus check.
+    fn encapsulate(
+        public_key: &Self::PublicKey,
+        m: [u8; 32],
+    ) -> Result<(Self::Ciphertext, [u8; 32]), Error> {
+        let ek = EncapsulationKey768::new(&(*public_key).into()).map_err(|_| Error::Input)?;
+        let (ct, ss) = ek.encapsulate_deterministic(&m.into());
+        Ok((ct.into(), ss.into()))
+    }
+    // Implicit rejection: a modified ciphertext gives a pseudorandom key,
+    // so the handshake then fails to decrypt rather than here.
+    fn decapsulate(&self, ciphertext: &Self::Ciphertext) -> Result<[u8; 32], Error> {
+        Ok(self.0.decapsulate(&(*ciphertext).into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn test_mlkem768() {
+        let kem = MlKem768::new([4u8; 64]);
+        let ek = kem.public_key();
+        let (mut ct, ss) = MlKem768::encapsulate(&ek, [5u8; 32]).unwrap();
+        assert_eq!(kem.decapsulate(&ct).unwrap(), ss);
+        // other randomness, other secret
+        let (_, other) = MlKem768::encapsulate(&ek, [6u8; 32]).unwrap();
+        assert_ne!(other, ss);
+        ct[0] ^= 1;
+        assert_ne!(kem.decapsulate(&ct).unwrap(), ss);
+    }
+
+    #[test]
+    fn test_mlkem768_modulus_check() {
+        let mut ek = MlKem768::new([1u8; 64]).public_key();
+        // the first coefficient becomes 4095
+        ek[0] = 0xff;
+        ek[1] |= 0x0f;
+        assert!(matches!(
+            MlKem768::encapsulate(&ek, [2u8; 32]),
+            Err(Error::Input)
+        ));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{Error, Transport};
+
+// type u8, stream id u16 LE, then the frame body
+pub const MUX_HEADER_LEN: usize = 3;
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_CLOSE: u8 = 2;
+// body: credit u32 LE
+const FRAME_WINDOW: u8 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MuxEvent {
+    Opened(u16),
+    // payload[..len] holds data for the stream
+    Data { id: u16, len: usize },
+    // the peer will send no more data on the stream
+    Closed(u16),
+    WindowUpdate(u16),
+    // a window update or close that crossed our close of the stream, after
+    // its slot was freed. Nothing to do.
+    Stale(u16),
+}
+
+#[derive(Clone, Copy)]
+struct Stream {
+    id: u16,
+    // bytes we may still send
+    send_window: u32,
+    // bytes the peer may still send
+    recv_window: u32,
+    // bytes read by the application but not yet credited to the peer
+    released: u32,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+// Multiplexes up to `S` concurrent streams over one `Transport`. Every frame
+// travels as a single transport message, so all framing is encrypted. Each
+// side may send up to `window` bytes on a stream before the peer credits it
+// back with window updates. The initiator opens odd stream ids and the
+// responder even ones, so both can open streams without coordination.
+pub struct Mux<const S: usize> {
+    transport: Transport,
+    streams: [Option<Stream>; S],
+    // the last `S` streams freed, to recognize late frames for them
+    freed: [Option<u16>; S],
+    freed_next: usize,
+    window: u32,
+    next_id: u16,
+}
+
+impl<const S: usize> Mux<S> {
+    pub fn new(transport: Transport, initiator: bool, window: u32) -> Self {
+        Self {
+            transport,
+            streams: [None; S],
+            freed: [None; S],
+            freed_next: 0,
+            window,
+            next_id: if initiator { 1 } else { 2 },
+        }
+    }
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+    pub fn into_transport(self) -> Transport {
+        self.transport
+    }
+    fn stream(&mut self, id: u16) -> Result<&mut Stream, Error> {
+        self.streams
+            .iter_mut()
+            .flatten()
+            .find(|s| s.id == id)
+            .ok_or(Error::Input)
+    }
+    fn insert(&mut self, id: u16) -> Result<(), Error> {
+        if self.streams.iter().flatten().any(|s| s.id == id) {
+            return Err(Error::Input);
+        }
+        // a reused id, after wrapping around, is live again
+        for freed in self.freed.iter_mut() {
+            if *freed == Some(id) {
+                *freed = None;
+            }
+        }
+        let slot = self
+            .streams
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(Error::QueueFull)?;
+        *slot = Some(Stream {
+            id,
+            send_window: self.window,
+            recv_window: self.window,
+            released: 0,
+            local_closed: false,
+            remote_closed: false,
+        });
+        Ok(())
+    }
+    // Frees the slot once both directions are closed.
+    fn reap(&mut self, id: u16) {
+        for slot in self.streams.iter_mut() {
+            if matches!(slot, Some(s) if s.id == id && s.local_closed && s.remote_closed) {
+                *slot = None;
+                self.freed[self.freed_next] = Some(id);
+                self.freed_next = (self.freed_next + 1) % S;
+            }
+        }
+    }
+    fn is_freed(&self, id: u16) -> bool {
+        self.freed.contains(&Some(id))
+    }
+    fn write_frame(
+        &mut self,
+        frame_type: u8,
+        id: u16,
+        body: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        let id = id.to_le_bytes();
+        self.transport
+            .write_vectored(&[&[frame_type, id[0], id[1]], body], message)
+    }
+    // Opens a new stream, returning its id and the message to send.
+    pub fn open(&mut self, message: &mut [u8]) -> Result<(u16, usize), Error> {
+        let id = self.next_id;
+        // nothing is encrypted for a stream that cannot be tracked, e.g. one
+        // whose id is still in use after wrapping around
+        self.insert(id)?;
+        let len = self
+            .write_frame(FRAME_OPEN, id, &[], message)
+            .inspect_err(|_| {
+                for slot in self.streams.iter_mut() {
+                    if matches!(slot, Some(s) if s.id == id) {
+                        *slot = None;
+                    }
+                }
+            })?;
+        self.next_id = self.next_id.wrapping_add(2);
+        Ok((id, len))
+    }
+    // Bytes that can be sent on the stream before the peer credits more.
+    pub fn send_window(&self, id: u16) -> Option<u32> {
+        self.streams
+            .iter()
+            .flatten()
+            .find(|s| s.id == id)
+            .map(|s| s.send_window)
+    }
+    pub fn write_stream(
+        &mut self,
+        id: u16,
+        data: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        let stream = self.stream(id)?;
+        if stream.local_closed {
+            return Err(Error::Closed);
+        }
+        let len = u32::try_from(data.len()).map_err(|_| Error::Input)?;
+        if len > stream.send_window {
+            return Err(Error::QueueFull);
+        }
+        let written = self.write_frame(FRAME_DATA, id, data, message)?;
+        self.stream(id)?.send_window -= len;
+        Ok(written)
+    }
+    pub fn close_stream(&mut self, id: u16, message: &mut [u8]) -> Result<usize, Error> {
+        if self.stream(id)?.local_closed {
+            return Err(Error::Closed);
+        }
+        let len = self.write_frame(FRAME_CLOSE, id, &[], message)?;
+        self.stream(id)?.local_closed = true;
+        self.reap(id);
+        Ok(len)
+    }
+    // Call after the application consumed `n` bytes of the stream. Returns
+    // a window update to send once half of the window has been consumed.
+    pub fn release(
+        &mut self,
+        id: u16,
+        n: usize,
+        message: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let window = self.window;
+        let stream = self.stream(id)?;
+        let n = u32::try_from(n).map_err(|_| Error::Input)?;
+        stream.released = stream.released.checked_add(n).ok_or(Error::Input)?;
+        if stream.released > window - stream.recv_window {
+            stream.released -= n;
+            return Err(Error::Input);
+        }
+        if stream.remote_closed || stream.released < window / 2 {
+            return Ok(None);
+        }
+        let credit = stream.released;
+        let len = self.write_frame(FRAME_WINDOW, id, &credit.to_le_bytes(), message)?;
+        let stream = self.stream(id)?;
+        stream.recv_window += credit;
+        stream.released = 0;
+        Ok(Some(len))
+    }
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<MuxEvent, Error> {
+        let len = self.transport.read_message(message, payload)?;
+        if len < MUX_HEADER_LEN {
+            return Err(Error::Input);
+        }
+        let id = u16::from_le_bytes([payload[1], payload[2]]);
+        let body_len = len - MUX_HEADER_LEN;
+        match payload[0] {
+            FRAME_OPEN => {
+                // streams opened by the peer have the other parity
+                if id % 2 == self.next_id % 2 || body_len != 0 {
+                    return Err(Error::Input);
+                }
+                self.insert(id)?;
+                Ok(MuxEvent::Opened(id))
+            }
+            FRAME_DATA => {
+                let stream = self.stream(id)?;
+                let n = body_len as u32;
+                if stream.remote_closed || n > stream.recv_window {
+                    return Err(Error::Input);
+                }
+                stream.recv_window -= n;
+                payload.copy_within(MUX_HEADER_LEN..len, 0);
+                Ok(MuxEvent::Data { id, len: body_len })
+            }
+            FRAME_CLOSE | FRAME_WINDOW if self.is_freed(id) => Ok(MuxEvent::Stale(id)),
+            FRAME_CLOSE => {
+                let stream = self.stream(id)?;
+                if stream.remote_closed {
+                    return Err(Error::Input);
+                }
+                stream.remote_closed = true;
+                self.reap(id);
+                Ok(MuxEvent::Closed(id))
+            }
+            FRAME_WINDOW => {
+                let credit = payload
+                    .get(MUX_HEADER_LEN..len)
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_le_bytes)
+                    .ok_or(Error::Input)?;
+                let stream = self.stream(id)?;
+                stream.send_window = stream.send_window.checked_add(credit).ok_or(Error::Input)?;
+                Ok(MuxEvent::WindowUpdate(id))
+            }
+            _ => Err(Error::Input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    fn pair() -> (Mux<4>, Mux<4>) {
+        let (init, resp) = transport_pair();
+        (Mux::new(init, true, 16), Mux::new(resp, false, 16))
+    }
+
+    #[test]
+    fn test_mux_streams() {
+        let (mut init, mut resp) = pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        let (config, len) = init.open(&mut message).unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Opened(config)
+        );
+        let (logs, len) = resp.open(&mut message).unwrap();
+        assert_ne!(config, logs);
+        assert_eq!(
+            init.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Opened(logs)
+        );
+
+        let len = init
+            .write_stream(config, b"mode=eco", &mut message)
+            .unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Data { id: config, len: 8 }
+        );
+        assert_eq!(&payload[..8], b"mode=eco");
+        let len = resp.write_stream(logs, b"boot ok", &mut message).unwrap();
+        assert_eq!(
+            init.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Data { id: logs, len: 7 }
+        );
+
+        let len = init.close_stream(config, &mut message).unwrap();
+        assert!(matches!(
+            init.write_stream(config, b"x", &mut message),
+            Err(Error::Closed)
+        ));
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Closed(config)
+        );
+        let len = resp.close_stream(config, &mut message).unwrap();
+        init.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(init.send_window(config), None);
+        assert_eq!(resp.send_window(config), None);
+
+        // a failed open takes no slot, `logs` holds one of the four
+        assert!(init.open(&mut message[..4]).is_err());
+        for _ in 0..3 {
+            init.open(&mut message).unwrap();
+        }
+        assert!(matches!(init.open(&mut message), Err(Error::QueueFull)));
+    }
+
+    #[test]
+    fn test_mux_flow_control() {
+        let (mut init, mut resp) = pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        let (id, len) = init.open(&mut message).unwrap();
+        resp.read_message(&message[..len], &mut payload).unwrap();
+        let len = init.write_stream(id, &[7u8; 12], &mut message).unwrap();
+        resp.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(init.send_window(id), Some(4));
+        assert!(matches!(
+            init.write_stream(id, &[7u8; 5], &mut message),
+            Err(Error::QueueFull)
+        ));
+
+        assert_eq!(resp.release(id, 4, &mut message).unwrap(), None);
+        let len = resp.release(id, 8, &mut message).unwrap().unwrap();
+        assert_eq!(
+            init.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::WindowUpdate(id)
+        );
+        assert_eq!(init.send_window(id), Some(16));
+        assert!(resp.release(id, 1, &mut message).is_err());
+    }
+
+    #[test]
+    fn test_mux_late_frames() {
+        let (mut init, mut resp) = pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        let (id, len) = init.open(&mut message).unwrap();
+        resp.read_message(&message[..len], &mut payload).unwrap();
+        let len = init.write_stream(id, &[7u8; 8], &mut message).unwrap();
+        resp.read_message(&message[..len], &mut payload).unwrap();
+
+        // both sides close at once, and the responder credits the data it
+        // read before it learns about the initiator's close
+        let close_init = init.close_stream(id, &mut message).unwrap();
+        let close_init = message[..close_init].to_vec();
+        let len = resp.close_stream(id, &mut message).unwrap();
+        assert_eq!(
+            init.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Closed(id)
+        );
+        assert_eq!(init.send_window(id), None);
+        let len = resp.release(id, 8, &mut message).unwrap().unwrap();
+        assert_eq!(
+            init.read_message(&message[..len], &mut payload).unwrap(),
+            MuxEvent::Stale(id)
+        );
+        assert_eq!(
+            resp.read_message(&close_init, &mut payload).unwrap(),
+            MuxEvent::Closed(id)
+        );
+
+        // frames for streams never opened are still rejected
+        let len = resp
+            .write_frame(super::FRAME_WINDOW, 99, &8u32.to_le_bytes(), &mut message)
+            .unwrap();
+        assert!(matches!(
+            init.read_message(&message[..len], &mut payload),
+            Err(Error::Input)
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+// Something happened on a handshake or transport. Events carry no keys,
+// payloads or handshake hashes, so they are safe to log anywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    // handshake message 1 to 3 read or written, 3 finishes the handshake
+    Handshake { initiator: bool, message: u8 },
+    // resumption message 1 or 2 read or written, 2 finishes it
+    Resumption { initiator: bool, message: u8 },
+    // a handshake or transport message failed to authenticate
+    DecryptFailed,
+    // a DH result was all zeros, the remote key is invalid
+    DhFailed,
+    Rekey { send: bool },
+    // transport message sent or received with this nonce
+    Sent { nonce: u64 },
+    Received { nonce: u64 },
+}
+
+// Receives the events of the handshakes and transports it is set on, see
+// `Handshake::set_observer`. Called inline, so keep it cheap.
+pub trait Observer: Sync {
+    fn event(&self, event: Event);
+}
+
+pub(crate) fn observe(observer: Option<&dyn Observer>, event: Event) {
+    if let Some(observer) = observer {
+        observer.event(event);
+    }
+}
+
+// Reports the failures among `result`'s errors.
+pub(crate) fn observe_result<T>(observer: Option<&dyn Observer>, result: &Result<T, crate::Error>) {
+    match result {
+        Err(crate::Error::Decrypt) => observe(observer, Event::DecryptFailed),
+        Err(crate::Error::Dh) => observe(observer, Event::DhFailed),
+        _ => {}
+    }
+}
+
+// Emits events to `tracing`: failures as warnings, handshake messages and
+// rekeys at debug level, transport messages at trace level.
+#[cfg(feature = "tracing")]
+pub struct TracingObserver;
+
+#[cfg(feature = "tracing")]
+impl Observer for TracingObserver {
+    fn event(&self, event: Event) {
+        match event {
+            Event::Handshake { initiator, message } => {
+                tracing::debug!(initiator, message, "noise handshake message")
+            }
+            Event::Resumption { initiator, message } => {
+                tracing::debug!(initiator, message, "noise resumption message")
+            }
+            Event::DecryptFailed => tracing::warn!("noise decrypt failed"),
+            Event::DhFailed => tracing::warn!("noise dh failed"),
+            Event::Rekey { send } => tracing::debug!(send, "noise rekey"),
+            Event::Sent { nonce } => tracing::trace!(nonce, "noise message sent"),
+            Event::Received { nonce } => tracing::trace!(nonce, "noise message received"),
+        }
+    }
+}
+
+// Counts events, e.g. as a static shared by all sessions of a gateway.
+#[cfg(target_has_atomic = "64")]
+#[derive(Default)]
+pub struct Counters {
+    pub handshake_messages: AtomicU64,
+    pub handshakes_completed: AtomicU64,
+    pub decrypt_failures: AtomicU64,
+    pub dh_failures: AtomicU64,
+    pub rekeys: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub messages_received: AtomicU64,
+    // highest nonce seen in either direction, to alert well before 2^64
+    pub max_nonce: AtomicU64,
+}
+
+#[cfg(target_has_atomic = "64")]
+impl Counters {
+    pub const fn new() -> Self {
+        Self {
+            handshake_messages: AtomicU64::new(0),
+            handshakes_completed: AtomicU64::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            dh_failures: AtomicU64::new(0),
+            rekeys: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            max_nonce: AtomicU64::new(0),
+        }
+    }
+    // Prometheus text exposition format, names prefixed with `noise_`.
+    pub fn write_prometheus(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        for (name, kind, value) in [
+            (
+                "handshake_messages_total",
+                "counter",
+                &self.handshake_messages,
+            ),
+            (
+                "handshakes_completed_total",
+                "counter",
+                &self.handshakes_completed,
+            ),
+            ("decrypt_failures_total", "counter", &self.decrypt_failures),
+            ("dh_failures_total", "counter", &self.dh_failures),
+            ("rekeys_total", "counter", &self.rekeys),
+            ("messages_sent_total", "counter", &self.messages_sent),
+            (
+                "messages_received_total",
+                "counter",
+                &self.messages_received,
+            ),
+            ("max_nonce", "gauge", &self.max_nonce),
+        ] {
+            writeln!(w, "# TYPE noise_{} {}", name, kind)?;
+            writeln!(w, "noise_{} {}", name, value.load(Relaxed))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_has_atomic = "64")]
+impl Observer for Counters {
+    fn event(&self, event: Event) {
+        let counter = match event {
+            Event::Handshake { message, .. } => {
+                if message == 3 {
+                    self.handshakes_completed.fetch_add(1, Relaxed);
+                }
+                &self.handshake_messages
+            }
+            Event::Resumption { message, .. } => {
+                if message == 2 {
+                    self.handshakes_completed.fetch_add(1, Relaxed);
+                }
+                &self.handshake_messages
+            }
+            Event::DecryptFailed => &self.decrypt_failures,
+            Event::DhFailed => &self.dh_failures,
+            Event::Rekey { .. } => &self.rekeys,
+            Event::Sent { nonce } => {
+                self.max_nonce.fetch_max(nonce, Relaxed);
+                &self.messages_sent
+            }
+            Event::Received { nonce } => {
+                self.max_nonce.fetch_max(nonce, Relaxed);
+                &self.messages_received
+            }
+        };
+        counter.fetch_add(1, Relaxed);
+    }
+}
+
+#[cfg(all(test, target_has_atomic = "64"))]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+    extern crate std;
+    use core::sync::atomic::Ordering::Relaxed;
+    use std::string::String;
+
+    #[test]
+    fn test_counters() {
+        static INIT: Counters = Counters::new();
+        static RESP: Counters = Counters::new();
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+        let (mut init, mut resp) = handshake_pair();
+        init.set_observer(&INIT);
+        resp.set_observer(&RESP);
+
+        exchange(&mut init, &mut resp);
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        buf_resp[len - 1] ^= 1;
+        assert!(init.read_message(&buf_resp[..len], &mut buf_init).is_err());
+        buf_resp[len - 1] ^= 1;
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        exchange(&mut init, &mut resp);
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        for _ in 0..3 {
+            let len = init.write_message(b"x", &mut buf_init).unwrap();
+            resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        }
+        init.rekey_send();
+        resp.rekey_recv();
+
+        assert_eq!(INIT.handshake_messages.load(Relaxed), 3);
+        assert_eq!(INIT.handshakes_completed.load(Relaxed), 1);
+        assert_eq!(INIT.decrypt_failures.load(Relaxed), 1);
+        assert_eq!(INIT.messages_sent.load(Relaxed), 3);
+        assert_eq!(RESP.messages_received.load(Relaxed), 3);
+        assert_eq!(RESP.max_nonce.load(Relaxed), 2);
+        assert_eq!(RESP.rekeys.load(Relaxed), 1);
+        assert_eq!(RESP.decrypt_failures.load(Relaxed), 0);
+
+        let mut text = String::new();
+        RESP.write_prometheus(&mut text).unwrap();
+        assert!(text.contains("# TYPE noise_rekeys_total counter\nnoise_rekeys_total 1\n"));
+        assert!(text.contains("noise_handshakes_completed_total 1\n"));
+    }
+
+    #[test]
+    fn test_dh_failure_event() {
+        static COUNTERS: Counters = Counters::new();
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+        let (_, mut resp) = handshake_pair();
+        resp.set_observer(&COUNTERS);
+        // a low order point as e makes ee all zeros
+        buf_init[..32].copy_from_slice(&[0u8; 32]);
+        resp.read_message(&buf_init[..32], &mut buf_resp).unwrap();
+        assert!(matches!(
+            resp.write_message(&[], &mut buf_resp),
+            Err(Error::Dh)
+        ));
+        assert_eq!(COUNTERS.dh_failures.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_resumption_events() {
+        static ISSUER: Counters = Counters::new();
+        static INIT: Counters = Counters::new();
+        let mut issuer = TicketIssuer::<1>::new([7u8; 32], 0, 100);
+        issuer.set_observer(&ISSUER);
+        let mut buf_init = [0u8; 300];
+        let mut buf_resp = [0u8; 300];
+        let (mut init, mut resp) = transport_pair();
+        let mut ticket = [0u8; TICKET_LEN];
+        issuer.issue(&resp, 0, &mut ticket).unwrap();
+        let len = resp.write_message(&ticket, &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let ticket = ResumptionTicket::new(&init, &buf_init[..len]).unwrap();
+
+        let mut init = Resumption::init([4u8; 32], &ticket);
+        init.set_observer(&INIT);
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        let (mut resp, _) = issuer
+            .accept([5u8; 32], &buf_init[..len], &mut buf_resp, 0)
+            .unwrap();
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"x", &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        for counters in [&INIT, &ISSUER] {
+            assert_eq!(counters.handshake_messages.load(Relaxed), 2);
+            assert_eq!(counters.handshakes_completed.load(Relaxed), 1);
+        }
+        assert_eq!(INIT.messages_sent.load(Relaxed), 1);
+        assert_eq!(ISSUER.messages_received.load(Relaxed), 1);
+    }
+
+    #[cfg(feature = "hfs")]
+    #[test]
+    fn test_hybrid_handshake_events() {
+        static COUNTERS: Counters = Counters::new();
+        let mut buf_init = [0u8; 3000];
+        let mut buf_resp = [0u8; 3000];
+        let kem = MlKem768::new([4u8; 64]);
+        let mut init = HybridHandshake::<_>::init([0u8; 32], [1u8; 32], kem, &[]);
+        let mut resp = HybridHandshake::<MlKem768>::resp([2u8; 32], [3u8; 32], [5u8; 32], &[]);
+        init.set_observer(&COUNTERS);
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+
+        let mut init = init.upgrade().unwrap();
+        init.write_message(b"x", &mut buf_init).unwrap();
+        assert_eq!(COUNTERS.handshakes_completed.load(Relaxed), 1);
+        assert_eq!(COUNTERS.messages_sent.load(Relaxed), 1);
+    }
+}
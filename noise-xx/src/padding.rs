@@ -0,0 +1,202 @@
+use blake2::Digest;
+use hkdf::Hkdf;
+
+use crate::Error;
+
+// pad length, u16 LE, at the end of the padded plaintext
+pub(crate) const TRAILER_LEN: usize = 2;
+const MAX_PAD: usize = u16::MAX as usize;
+
+// Hides payload lengths by padding the plaintext before encryption, so the
+// padding is authenticated like the payload. With any policy other than
+// `None` the plaintext is `payload || zeros || pad_len`, and both peers
+// must use padding for the receiver to strip it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    // round the padded plaintext up to a multiple of the block size
+    Block(usize),
+    // pad every plaintext to exactly this size
+    Fixed(usize),
+    // add between `min` and `max` bytes, chosen by a keyed hash so an
+    // observer cannot predict them
+    Random {
+        min: usize,
+        max: usize,
+    },
+}
+
+// The key for `Padding::Random`, so the lengths never depend directly on a
+// key used for anything else.
+pub(crate) fn derive_key(secret: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<blake2::Blake2s>::new(None, secret)
+        .expand(label, &mut key)
+        .unwrap();
+    key
+}
+
+impl Padding {
+    // Length of the padded plaintext for a payload of `len` bytes.
+    pub(crate) fn padded_len(
+        &self,
+        len: usize,
+        key: &[u8; 32],
+        counter: u64,
+    ) -> Result<usize, Error> {
+        let min = len.checked_add(TRAILER_LEN).ok_or(Error::Input)?;
+        let padded = match *self {
+            Self::None => return Ok(len),
+            Self::Block(block) if block > 0 => {
+                min.div_ceil(block).checked_mul(block).ok_or(Error::Input)?
+            }
+            Self::Fixed(size) if size >= min => size,
+            // bounded so neither the range nor the sum can overflow
+            Self::Random { min: lo, max: hi } if lo <= hi && hi <= MAX_PAD => {
+                let mut hash = blake2::Blake2s::new();
+                hash.update(key);
+                hash.update(counter.to_le_bytes());
+                let r = hash.finalize();
+                let r = u64::from_le_bytes(r[..8].try_into().unwrap());
+                let pad = lo + (r % (hi - lo + 1) as u64) as usize;
+                min.checked_add(pad).ok_or(Error::Input)?
+            }
+            _ => return Err(Error::Input),
+        };
+        if padded - min > MAX_PAD {
+            return Err(Error::Input);
+        }
+        Ok(padded)
+    }
+    // Pads buf[..len] in place to `padded` bytes.
+    pub(crate) fn pad(&self, buf: &mut [u8], len: usize, padded: usize) -> Result<(), Error> {
+        if *self == Self::None {
+            return Ok(());
+        }
+        let buf = buf.get_mut(..padded).ok_or(Error::Input)?;
+        let pad = padded - len - TRAILER_LEN;
+        buf[len..len + pad].fill(0);
+        buf[len + pad..].copy_from_slice(&(pad as u16).to_le_bytes());
+        Ok(())
+    }
+    // Length of the payload in the padded plaintext buf[..len].
+    pub(crate) fn unpad(&self, buf: &[u8], len: usize) -> Result<usize, Error> {
+        if *self == Self::None {
+            return Ok(len);
+        }
+        if len < TRAILER_LEN {
+            return Err(Error::Input);
+        }
+        let pad = u16::from_le_bytes([buf[len - 2], buf[len - 1]]) as usize;
+        (len - TRAILER_LEN).checked_sub(pad).ok_or(Error::Input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    fn padded_pair(padding: Padding) -> (Transport, Transport, [usize; 3]) {
+        let mut buf_init = [0u8; 200];
+        let mut buf_resp = [0u8; 200];
+        let mut lens = [0; 3];
+
+        let (mut init, mut resp) = handshake_pair();
+        init.set_padding(padding);
+        resp.set_padding(padding);
+
+        lens[0] = init.write_message(b"a", &mut buf_init).unwrap();
+        let len = resp
+            .read_message(&buf_init[..lens[0]], &mut buf_resp)
+            .unwrap();
+        assert_eq!(&buf_resp[..len], b"a");
+        lens[1] = resp.write_message(b"bb", &mut buf_resp).unwrap();
+        let len = init
+            .read_message(&buf_resp[..lens[1]], &mut buf_init)
+            .unwrap();
+        assert_eq!(&buf_init[..len], b"bb");
+        lens[2] = init.write_message(b"ccc", &mut buf_init).unwrap();
+        let len = resp
+            .read_message(&buf_init[..lens[2]], &mut buf_resp)
+            .unwrap();
+        assert_eq!(&buf_resp[..len], b"ccc");
+
+        (init.upgrade().unwrap(), resp.upgrade().unwrap(), lens)
+    }
+
+    #[test]
+    fn test_padding_policies() {
+        let (mut init, mut resp, lens) = padded_pair(Padding::Block(16));
+        assert_eq!(lens, [32 + 16, 96 - 16 + 16 + 16, 64 - 16 + 16 + 16]);
+        let mut message = [0u8; 200];
+        let mut payload = [0u8; 200];
+        for data in [&b"t=21.5"[..], b"humidity=40%"] {
+            let len = init.write_message(data, &mut message).unwrap();
+            assert_eq!(len, 16 + 16);
+            let len = resp.read_message(&message[..len], &mut payload).unwrap();
+            assert_eq!(&payload[..len], data);
+        }
+
+        let (mut init, mut resp, _) = padded_pair(Padding::Fixed(64));
+        let len = init.write_message(b"x", &mut message).unwrap();
+        assert_eq!(len, 64 + 16);
+        let len = resp.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"x");
+        assert!(init.write_message(&[0u8; 63], &mut message).is_err());
+
+        let (mut init, mut resp, _) = padded_pair(Padding::Random { min: 4, max: 40 });
+        let mut seen = [false; 41];
+        for _ in 0..64 {
+            let len = init.write_message(b"x", &mut message).unwrap();
+            let pad = len - 1 - 2 - 16;
+            assert!((4..=40).contains(&pad));
+            seen[pad] = true;
+            let len = resp.read_message(&message[..len], &mut payload).unwrap();
+            assert_eq!(&payload[..len], b"x");
+        }
+        assert!(seen.iter().filter(|s| **s).count() > 1);
+
+        for padding in [
+            Padding::Random {
+                min: 0,
+                max: usize::MAX,
+            },
+            Padding::Block(usize::MAX),
+            Padding::Fixed(usize::MAX),
+        ] {
+            init.set_padding(padding);
+            assert!(matches!(
+                init.write_message(b"x", &mut message),
+                Err(Error::Input)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_padding_keys() {
+        // the two directions pick their lengths independently
+        let (mut init, mut resp, _) = padded_pair(Padding::Random { min: 0, max: 100 });
+        let mut message = [0u8; 200];
+        let mut lens = [(0, 0); 8];
+        for len in lens.iter_mut() {
+            len.0 = init.write_message(&[], &mut message).unwrap();
+            len.1 = resp.write_message(&[], &mut message).unwrap();
+        }
+        assert!(lens.iter().any(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn test_padding_is_authenticated() {
+        let (mut init, mut resp, _) = padded_pair(Padding::Block(32));
+        let mut message = [0u8; 200];
+        let mut payload = [0u8; 200];
+        let len = init.write_message(b"x", &mut message).unwrap();
+        message[len - 17] ^= 1;
+        assert!(matches!(
+            resp.read_message(&message[..len], &mut payload),
+            Err(Error::Decrypt)
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+use core::fmt;
+use core::str::FromStr;
+
+use blake2::Digest;
+
+use crate::Error;
+
+pub const BECH32_HRP: &str = "liot";
+// hrp, separator, 52 groups of 5 bits, 6 checksum characters
+pub const BECH32_LEN: usize = 4 + 1 + 52 + 6;
+pub const BASE64URL_LEN: usize = 43;
+pub const FINGERPRINT_LEN: usize = 8;
+
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BASE64URL_CHARSET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// A remote static key. Displays as bech32, e.g. `liot1...`, and parses from
+// bech32, unpadded base64url or hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey(pub [u8; 32]);
+
+// BIP 173 checksum
+fn bech32_polymod(values: impl Iterator<Item = u8>) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk = 1u32;
+    for v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ v as u32;
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn bech32_hrp_expand() -> impl Iterator<Item = u8> {
+    let hrp = BECH32_HRP.bytes();
+    hrp.clone()
+        .map(|c| c >> 5)
+        .chain(core::iter::once(0))
+        .chain(hrp.map(|c| c & 31))
+}
+
+// the key as 52 groups of 5 bits, the last one padded with zeros
+fn to_groups(key: &[u8; 32]) -> [u8; 52] {
+    let mut groups = [0u8; 52];
+    for (i, g) in groups.iter_mut().enumerate() {
+        let bit = i * 5;
+        let hi = key[bit / 8] as u16;
+        let lo = key.get(bit / 8 + 1).copied().unwrap_or(0) as u16;
+        *g = (((hi << 8 | lo) >> (11 - bit % 8)) & 31) as u8;
+    }
+    groups
+}
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    // First bytes of BLAKE2s(key), short enough to read out over the phone.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let hash = blake2::Blake2s::digest(&self.0);
+        Fingerprint(hash[..FINGERPRINT_LEN].try_into().unwrap())
+    }
+    pub fn to_bech32<'a>(&self, out: &'a mut [u8; BECH32_LEN]) -> &'a str {
+        let groups = to_groups(&self.0);
+        let checksum = bech32_polymod(
+            bech32_hrp_expand()
+                .chain(groups.iter().copied())
+                .chain([0u8; 6]),
+        ) ^ 1;
+        out[..4].copy_from_slice(BECH32_HRP.as_bytes());
+        out[4] = b'1';
+        for (o, g) in out[5..57].iter_mut().zip(groups) {
+            *o = BECH32_CHARSET[g as usize];
+        }
+        for (i, o) in out[57..].iter_mut().enumerate() {
+            *o = BECH32_CHARSET[(checksum >> (5 * (5 - i)) & 31) as usize];
+        }
+        core::str::from_utf8(out).unwrap()
+    }
+    // Lowercase only, as written by `to_bech32`.
+    pub fn from_bech32(s: &str) -> Result<Self, Error> {
+        let data = s
+            .strip_prefix(BECH32_HRP)
+            .and_then(|s| s.strip_prefix('1'))
+            .filter(|d| d.len() == BECH32_LEN - 5)
+            .ok_or(Error::Input)?;
+        let mut values = [0u8; BECH32_LEN - 5];
+        for (v, c) in values.iter_mut().zip(data.bytes()) {
+            *v = BECH32_CHARSET
+                .iter()
+                .position(|&b| b == c)
+                .ok_or(Error::Input)? as u8;
+        }
+        if bech32_polymod(bech32_hrp_expand().chain(values.iter().copied())) != 1 {
+            return Err(Error::Input);
+        }
+        let groups: [u8; 52] = values[..52].try_into().unwrap();
+        Self::from_groups(&groups)
+    }
+    fn from_groups(groups: &[u8; 52]) -> Result<Self, Error> {
+        let mut key = [0u8; 32];
+        let mut acc = 0u32;
+        let mut bits = 0;
+        let mut i = 0;
+        for &g in groups {
+            acc = acc << 5 | g as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                key[i] = (acc >> bits) as u8;
+                i += 1;
+            }
+        }
+        // the 4 padding bits must be zero
+        if acc & ((1 << bits) - 1) != 0 {
+            return Err(Error::Input);
+        }
+        Ok(Self(key))
+    }
+    pub fn to_base64url<'a>(&self, out: &'a mut [u8; BASE64URL_LEN]) -> &'a str {
+        for (i, o) in out.iter_mut().enumerate() {
+            let bit = i * 6;
+            let hi = self.0[bit / 8] as u16;
+            let lo = self.0.get(bit / 8 + 1).copied().unwrap_or(0) as u16;
+            *o = BASE64URL_CHARSET[((hi << 8 | lo) >> (10 - bit % 8)) as usize & 63];
+        }
+        core::str::from_utf8(out).unwrap()
+    }
+    // Unpadded, as written by `to_base64url`.
+    pub fn from_base64url(s: &str) -> Result<Self, Error> {
+        if s.len() != BASE64URL_LEN {
+            return Err(Error::Input);
+        }
+        let mut key = [0u8; 32];
+        let mut acc = 0u32;
+        let mut bits = 0;
+        let mut i = 0;
+        for c in s.bytes() {
+            let v = BASE64URL_CHARSET
+                .iter()
+                .position(|&b| b == c)
+                .ok_or(Error::Input)?;
+            acc = acc << 6 | v as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                key[i] = (acc >> bits) as u8;
+                i += 1;
+            }
+        }
+        if acc & ((1 << bits) - 1) != 0 {
+            return Err(Error::Input);
+        }
+        Ok(Self(key))
+    }
+    pub fn from_hex(s: &str) -> Result<Self, Error> {
+        if s.len() != 64 {
+            return Err(Error::Input);
+        }
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            let byte = s.get(2 * i..2 * i + 2).ok_or(Error::Input)?;
+            *b = u8::from_str_radix(byte, 16).map_err(|_| Error::Input)?;
+        }
+        Ok(Self(key))
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl From<PublicKey> for [u8; 32] {
+    fn from(key: PublicKey) -> Self {
+        key.0
+    }
+}
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<[u8; 32]> for PublicKey {
+    fn eq(&self, other: &[u8; 32]) -> bool {
+        &self.0 == other
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.to_bech32(&mut [0u8; BECH32_LEN]))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.len() {
+            BECH32_LEN => Self::from_bech32(s),
+            BASE64URL_LEN => Self::from_base64url(s),
+            _ => Self::from_hex(s),
+        }
+    }
+}
+
+// Displays as four dash-separated groups of hex, e.g. `1a2b-3c4d-5e6f-7a8b`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint(pub [u8; FINGERPRINT_LEN]);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, pair) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}{:02x}", pair[0], pair[1])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    extern crate std;
+    use std::string::ToString;
+
+    #[test]
+    fn test_public_key_encodings() {
+        for bytes in [[0u8; 32], [0xffu8; 32], [1u8; 32].public_key()] {
+            let key = PublicKey(bytes);
+            let bech32 = key.to_string();
+            assert!(bech32.starts_with("liot1"));
+            assert_eq!(bech32.len(), BECH32_LEN);
+            assert_eq!(bech32.parse::<PublicKey>().unwrap(), key);
+            let base64url = key.to_base64url(&mut [0u8; BASE64URL_LEN]).to_string();
+            assert_eq!(base64url.parse::<PublicKey>().unwrap(), key);
+            let hex: std::string::String =
+                bytes.iter().map(|b| std::format!("{:02x}", b)).collect();
+            assert_eq!(hex.parse::<PublicKey>().unwrap(), key);
+        }
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let key = PublicKey(bytes);
+        assert_eq!(
+            key.to_string(),
+            "liot1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0s8emczr"
+        );
+        assert_eq!(
+            key.to_base64url(&mut [0u8; BASE64URL_LEN]),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8"
+        );
+        assert_eq!(
+            PublicKey([0xfbu8; 32]).to_base64url(&mut [0u8; BASE64URL_LEN]),
+            "-_v7-_v7-_v7-_v7-_v7-_v7-_v7-_v7-_v7-_v7-_s"
+        );
+        assert_eq!(key.fingerprint().to_string().len(), 4 * 4 + 3);
+    }
+
+    #[test]
+    fn test_bech32_checksum() {
+        let key = PublicKey([1u8; 32].public_key());
+        let mut s = key.to_string().into_bytes();
+        // any single character change is caught
+        for i in 5..s.len() {
+            let c = s[i];
+            s[i] = if c == b'q' { b'p' } else { b'q' };
+            let changed = std::str::from_utf8(&s).unwrap();
+            assert!(matches!(PublicKey::from_bech32(changed), Err(Error::Input)));
+            s[i] = c;
+        }
+        assert!("liot1".parse::<PublicKey>().is_err());
+        assert!("xyz".parse::<PublicKey>().is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{Error, PublicKey, Transport};
+
+// First byte of every encrypted payload when the record layer is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    Data = 0,
+    Keepalive = 1,
+    // authenticated end of stream, nothing follows it
+    Close = 2,
+    // the sender rekeys its sending key after this record, so the receiver
+    // rekeys its receiving key after reading it
+    Rekey = 3,
+    // the sender will use the static key in the body from now on
+    Rotate = 4,
+    // the receiver of a rotate record stored the key in the body
+    RotateAck = 5,
+}
+
+impl RecordType {
+    pub(crate) fn from_u8(t: u8) -> Result<Self, Error> {
+        match t {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Keepalive),
+            2 => Ok(Self::Close),
+            3 => Ok(Self::Rekey),
+            4 => Ok(Self::Rotate),
+            5 => Ok(Self::RotateAck),
+            _ => Err(Error::Input),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Record {
+    // payload[..len]
+    Data(usize),
+    Keepalive,
+    Close,
+    Rekey,
+    Rotate(PublicKey),
+    RotateAck(PublicKey),
+}
+
+// Times are in caller-defined ticks, usually milliseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct RecordConfig {
+    // send a keepalive when nothing was sent for this long
+    pub keepalive: u64,
+    // give up when nothing was received for this long
+    pub idle: u64,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        Self {
+            keepalive: 15_000,
+            idle: 60_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Timer {
+    Idle,
+    SendKeepalive,
+}
+
+// Typed records on top of `Transport`. A peer that stops sending without a
+// close record is distinguishable from one that closed, see `finish`.
+pub struct RecordTransport {
+    transport: Transport,
+    config: RecordConfig,
+    last_sent: u64,
+    last_received: u64,
+    sent_close: bool,
+    received_close: bool,
+}
+
+impl RecordTransport {
+    pub fn new(transport: Transport, config: RecordConfig, now: u64) -> Self {
+        Self {
+            transport,
+            config,
+            last_sent: now,
+            last_received: now,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+    pub fn into_transport(self) -> Transport {
+        self.transport
+    }
+    pub fn is_closed(&self) -> bool {
+        self.sent_close && self.received_close
+    }
+    fn write_record(
+        &mut self,
+        record_type: RecordType,
+        data: &[u8],
+        message: &mut [u8],
+        now: u64,
+    ) -> Result<usize, Error> {
+        if self.sent_close {
+            return Err(Error::Closed);
+        }
+        let len = self
+            .transport
+            .write_vectored(&[&[record_type as u8], data], message)?;
+        self.last_sent = now;
+        match record_type {
+            RecordType::Close => self.sent_close = true,
+            RecordType::Rekey => self.transport.rekey_send(),
+            _ => {}
+        }
+        Ok(len)
+    }
+    pub fn write_data(
+        &mut self,
+        data: &[u8],
+        message: &mut [u8],
+        now: u64,
+    ) -> Result<usize, Error> {
+        self.write_record(RecordType::Data, data, message, now)
+    }
+    pub fn write_keepalive(&mut self, message: &mut [u8], now: u64) -> Result<usize, Error> {
+        self.write_record(RecordType::Keepalive, &[], message, now)
+    }
+    pub fn write_close(&mut self, message: &mut [u8], now: u64) -> Result<usize, Error> {
+        self.write_record(RecordType::Close, &[], message, now)
+    }
+    pub fn write_rekey(&mut self, message: &mut [u8], now: u64) -> Result<usize, Error> {
+        self.write_record(RecordType::Rekey, &[], message, now)
+    }
+    // Announces the next static key. The peer should record it in place of
+    // the current one and acknowledge it, only then may the sender drop the
+    // current key, see `KeyRing`.
+    pub fn write_rotate(
+        &mut self,
+        next_key: &PublicKey,
+        message: &mut [u8],
+        now: u64,
+    ) -> Result<usize, Error> {
+        self.write_record(RecordType::Rotate, next_key.as_bytes(), message, now)
+    }
+    pub fn write_rotate_ack(
+        &mut self,
+        key: &PublicKey,
+        message: &mut [u8],
+        now: u64,
+    ) -> Result<usize, Error> {
+        self.write_record(RecordType::RotateAck, key.as_bytes(), message, now)
+    }
+    pub fn read_message(
+        &mut self,
+        message: &[u8],
+        payload: &mut [u8],
+        now: u64,
+    ) -> Result<Record, Error> {
+        if self.received_close {
+            return Err(Error::Closed);
+        }
+        let len = self.transport.read_message(message, payload)?;
+        if len == 0 {
+            return Err(Error::Input);
+        }
+        self.last_received = now;
+        Ok(match RecordType::from_u8(payload[0])? {
+            RecordType::Data => {
+                payload.copy_within(1..len, 0);
+                Record::Data(len - 1)
+            }
+            RecordType::Keepalive => Record::Keepalive,
+            RecordType::Close => {
+                self.received_close = true;
+                Record::Close
+            }
+            RecordType::Rekey => {
+                self.transport.rekey_recv();
+                Record::Rekey
+            }
+            RecordType::Rotate => Record::Rotate(
+                payload
+                    .get(1..len)
+                    .and_then(|k| k.try_into().ok())
+                    .map(PublicKey)
+                    .ok_or(Error::Input)?,
+            ),
+            RecordType::RotateAck => Record::RotateAck(
+                payload
+                    .get(1..len)
+                    .and_then(|k| k.try_into().ok())
+                    .map(PublicKey)
+                    .ok_or(Error::Input)?,
+            ),
+        })
+    }
+    // When `tick` next needs to be called.
+    pub fn poll_timeout(&self) -> u64 {
+        let idle = self.last_received.saturating_add(self.config.idle);
+        if self.sent_close {
+            return idle;
+        }
+        idle.min(self.last_sent.saturating_add(self.config.keepalive))
+    }
+    pub fn tick(&mut self, now: u64) -> Option<Timer> {
+        if !self.received_close && now >= self.last_received.saturating_add(self.config.idle) {
+            return Some(Timer::Idle);
+        }
+        if !self.sent_close && now >= self.last_sent.saturating_add(self.config.keepalive) {
+            return Some(Timer::SendKeepalive);
+        }
+        None
+    }
+    // Call when the underlying stream ended. Fails unless the peer closed
+    // the session with a close record, i.e. the stream was truncated.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.received_close {
+            Ok(())
+        } else {
+            Err(Error::Truncated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    fn pair() -> (RecordTransport, RecordTransport) {
+        let (init, resp) = transport_pair();
+        let config = RecordConfig {
+            keepalive: 10,
+            idle: 30,
+        };
+        (
+            RecordTransport::new(init, config, 0),
+            RecordTransport::new(resp, config, 0),
+        )
+    }
+
+    #[test]
+    fn test_records() {
+        let (mut init, mut resp) = pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        let len = init.write_data(b"reading", &mut message, 1).unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload, 1).unwrap(),
+            Record::Data(7)
+        );
+        assert_eq!(&payload[..7], b"reading");
+
+        let len = init.write_rekey(&mut message, 2).unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload, 2).unwrap(),
+            Record::Rekey
+        );
+        let len = init.write_data(b"after", &mut message, 3).unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload, 3).unwrap(),
+            Record::Data(5)
+        );
+
+        assert!(matches!(resp.finish(), Err(Error::Truncated)));
+        let len = init.write_close(&mut message, 4).unwrap();
+        assert!(matches!(
+            init.write_data(b"x", &mut message, 4),
+            Err(Error::Closed)
+        ));
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload, 4).unwrap(),
+            Record::Close
+        );
+        resp.finish().unwrap();
+    }
+
+    #[test]
+    fn test_record_timers() {
+        let (mut init, mut resp) = pair();
+        let mut message = [0u8; 100];
+        let mut payload = [0u8; 100];
+
+        assert_eq!(init.poll_timeout(), 10);
+        assert_eq!(init.tick(9), None);
+        assert_eq!(init.tick(10), Some(Timer::SendKeepalive));
+        let len = init.write_keepalive(&mut message, 10).unwrap();
+        assert_eq!(
+            resp.read_message(&message[..len], &mut payload, 10)
+                .unwrap(),
+            Record::Keepalive
+        );
+        assert_eq!(init.poll_timeout(), 20);
+        assert_eq!(resp.tick(39), Some(Timer::SendKeepalive));
+        assert_eq!(resp.tick(40), Some(Timer::Idle));
+    }
+}
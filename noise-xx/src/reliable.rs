@@ -0,0 +1,254 @@
+use blake2::Digest;
+
+use crate::{Error, Handshake, Transport};
+
+// Times are in caller-defined ticks, usually milliseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct RetransmitConfig {
+    pub initial: u64,
+    pub max: u64,
+    pub deadline: u64,
+}
+
+impl Default for RetransmitConfig {
+    fn default() -> Self {
+        Self {
+            initial: 1_000,
+            max: 8_000,
+            deadline: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received<'a> {
+    // payload[..len] of a new handshake message
+    Payload(usize),
+    // a message already processed, answer with the cached message (may be empty)
+    Duplicate(&'a [u8]),
+}
+
+// Sans-IO driver making the XX handshake survive message loss and
+// duplication. The last message sent is cached in an `N` byte buffer and
+// sent again with exponential backoff while a reply is outstanding, or when
+// the peer repeats the message it was a reply to.
+//
+// The initiator is finished after writing message 3 but should keep the
+// driver around until it hears from the responder, because if message 3 is
+// lost the responder will repeat message 2.
+pub struct ReliableHandshake<const N: usize> {
+    handshake: Option<Handshake>,
+    transport: Option<Transport>,
+    config: RetransmitConfig,
+    deadline: u64,
+    sent: [u8; N],
+    sent_len: usize,
+    received: Option<[u8; 32]>,
+    interval: u64,
+    retransmit_at: Option<u64>,
+}
+
+impl<const N: usize> ReliableHandshake<N> {
+    pub fn new(handshake: Handshake, config: RetransmitConfig, now: u64) -> Self {
+        Self {
+            handshake: Some(handshake),
+            transport: None,
+            config,
+            deadline: now.saturating_add(config.deadline),
+            sent: [0; N],
+            sent_len: 0,
+            received: None,
+            interval: config.initial,
+            retransmit_at: None,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.transport.is_some()
+    }
+    pub fn transport(&mut self) -> Option<&mut Transport> {
+        self.transport.as_mut()
+    }
+    pub fn into_transport(self) -> Option<Transport> {
+        self.transport
+    }
+    // When `tick` next needs to be called.
+    pub fn poll_timeout(&self) -> Option<u64> {
+        if self.is_finished() {
+            return None;
+        }
+        Some(
+            self.retransmit_at
+                .map_or(self.deadline, |t| t.min(self.deadline)),
+        )
+    }
+    fn finish_if_done(&mut self) -> Result<(), Error> {
+        let done = self
+            .handshake
+            .as_ref()
+            .is_some_and(|h| h.handshake_hash().is_ok());
+        if done {
+            self.transport = Some(self.handshake.take().unwrap().upgrade()?);
+            self.retransmit_at = None;
+        }
+        Ok(())
+    }
+    pub fn write_message(&mut self, payload: &[u8], now: u64) -> Result<&[u8], Error> {
+        let handshake = self.handshake.as_mut().ok_or(Error::NeedUpgrade)?;
+        let len = handshake.write_message(payload, &mut self.sent)?;
+        self.sent_len = len;
+        self.interval = self.config.initial;
+        self.retransmit_at = Some(now.saturating_add(self.interval));
+        self.finish_if_done()?;
+        Ok(&self.sent[..self.sent_len])
+    }
+    pub fn read_message(
+        &mut self,
+        message: &[u8],
+        payload: &mut [u8],
+        now: u64,
+    ) -> Result<Received<'_>, Error> {
+        let digest: [u8; 32] = blake2::Blake2s::digest(message).into();
+        if self.received == Some(digest) {
+            return Ok(Received::Duplicate(&self.sent[..self.sent_len]));
+        }
+        // the deadline is the first tick at which the handshake has failed,
+        // as in `tick`
+        if now >= self.deadline && !self.is_finished() {
+            return Err(Error::Timeout);
+        }
+        let handshake = self.handshake.as_mut().ok_or(Error::NeedUpgrade)?;
+        let len = handshake.read_message(message, payload)?;
+        self.received = Some(digest);
+        // our last message got through, nothing to repeat until we send again
+        self.retransmit_at = None;
+        self.finish_if_done()?;
+        if self.is_finished() {
+            // message 3 needs no reply, answering a duplicate of it with
+            // message 2 would make the initiator send message 3 again
+            self.sent_len = 0;
+        }
+        Ok(Received::Payload(len))
+    }
+    // Returns the message to send again, if its timer has fired.
+    pub fn tick(&mut self, now: u64) -> Result<Option<&[u8]>, Error> {
+        if self.is_finished() {
+            return Ok(None);
+        }
+        if now >= self.deadline {
+            return Err(Error::Timeout);
+        }
+        match self.retransmit_at {
+            Some(at) if now >= at => {
+                self.interval = self.interval.saturating_mul(2).min(self.config.max);
+                self.retransmit_at = Some(now.saturating_add(self.interval));
+                Ok(Some(&self.sent[..self.sent_len]))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    #[test]
+    fn test_reliable_handshake_with_loss() {
+        let config = RetransmitConfig::default();
+        let (init, resp) = handshake_pair();
+        let mut init = ReliableHandshake::<128>::new(init, config, 0);
+        let mut resp = ReliableHandshake::<128>::new(resp, config, 0);
+        let mut payload = [0u8; 128];
+
+        // message 1 is lost and sent again after the initial interval
+        init.write_message(b"one", 0).unwrap();
+        assert_eq!(init.poll_timeout(), Some(1_000));
+        assert_eq!(init.tick(999).unwrap(), None);
+        let msg1 = init.tick(1_000).unwrap().unwrap().to_vec();
+        assert_eq!(init.poll_timeout(), Some(3_000));
+        assert_eq!(
+            resp.read_message(&msg1, &mut payload, 1_000).unwrap(),
+            Received::Payload(3)
+        );
+
+        // message 2 is lost, the repeated message 1 is answered again
+        let msg2 = resp.write_message(b"two", 1_000).unwrap().to_vec();
+        let msg1 = init.tick(3_000).unwrap().unwrap().to_vec();
+        assert_eq!(
+            resp.read_message(&msg1, &mut payload, 3_000).unwrap(),
+            Received::Duplicate(&msg2[..])
+        );
+        assert_eq!(
+            init.read_message(&msg2, &mut payload, 3_000).unwrap(),
+            Received::Payload(3)
+        );
+
+        // message 3 is lost, the responder repeats message 2
+        let msg3 = init.write_message(b"three", 3_000).unwrap().to_vec();
+        assert!(init.is_finished());
+        assert_eq!(init.tick(100_000).unwrap(), None);
+        let again = resp.tick(3_000).unwrap().unwrap().to_vec();
+        assert_eq!(again, msg2);
+        assert_eq!(
+            init.read_message(&again, &mut payload, 3_000).unwrap(),
+            Received::Duplicate(&msg3[..])
+        );
+        assert_eq!(
+            resp.read_message(&msg3, &mut payload, 3_000).unwrap(),
+            Received::Payload(5)
+        );
+        assert!(resp.is_finished());
+        // a duplicated message 3 is answered with nothing
+        assert_eq!(
+            resp.read_message(&msg3, &mut payload, 3_000).unwrap(),
+            Received::Duplicate(&[])
+        );
+
+        let mut init = init.into_transport().unwrap();
+        let mut resp = resp.into_transport().unwrap();
+        let len = init.write_message(b"hello", &mut payload).unwrap();
+        let mut out = [0u8; 128];
+        let len = resp.read_message(&payload[..len], &mut out).unwrap();
+        assert_eq!(&out[..len], b"hello");
+    }
+
+    #[test]
+    fn test_reliable_handshake_deadline() {
+        let config = RetransmitConfig {
+            initial: 10,
+            max: 40,
+            deadline: 100,
+        };
+        let (init, _) = handshake_pair();
+        let mut init = ReliableHandshake::<128>::new(init, config, 0);
+        init.write_message(&[], 0).unwrap();
+        let mut resends = 0;
+        let now = loop {
+            let now = init.poll_timeout().unwrap();
+            match init.tick(now) {
+                Ok(Some(_)) => resends += 1,
+                Ok(None) => {}
+                Err(Error::Timeout) => break now,
+                Err(e) => panic!("{:?}", e),
+            }
+        };
+        assert_eq!(now, 100);
+        // 10, 30, 70
+        assert_eq!(resends, 3);
+
+        // a reply arriving just before the deadline is read, one arriving at
+        // the deadline is not, whichever call notices it first
+        for (now, ok) in [(99, true), (100, false)] {
+            let (init, mut resp) = handshake_pair();
+            let mut init = ReliableHandshake::<128>::new(init, config, 0);
+            let mut payload = [0u8; 128];
+            let msg1 = init.write_message(&[], 0).unwrap().to_vec();
+            resp.read_message(&msg1, &mut payload).unwrap();
+            let len = resp.write_message(&[], &mut payload).unwrap();
+            let msg2 = payload[..len].to_vec();
+            assert_eq!(init.tick(now).is_ok(), ok);
+            assert_eq!(init.read_message(&msg2, &mut payload, now).is_ok(), ok);
+        }
+    }
+}
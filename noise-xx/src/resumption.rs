@@ -0,0 +1,486 @@
+use crate::{
+    cipher_state::TAG_LEN,
+    handshake::DH_LEN,
+    observer::{observe, observe_result},
+    x25519::{pub_key, x25519},
+    CipherState, Error, Event, Observer, PublicKey, SymmetricState, Transport,
+};
+
+const PROT_NAME: &[u8] = b"Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+const PSK_LABEL: &[u8] = b"resumption";
+
+// id u64 LE, then psk, remote static key and not_after, encrypted
+const TICKET_PLAIN_LEN: usize = 32 + 32 + 8;
+pub const TICKET_LEN: usize = 8 + TICKET_PLAIN_LEN + TAG_LEN;
+// message 1 is the ticket, e and the encrypted early data
+pub const RESUME_MSG1_OVERHEAD: usize = TICKET_LEN + DH_LEN + TAG_LEN;
+// message 2 is e and the encrypted payload
+pub const RESUME_MSG2_OVERHEAD: usize = DH_LEN + TAG_LEN;
+
+fn derive_psk(transport: &Transport, id: &[u8]) -> Result<[u8; 32], Error> {
+    let mut psk = [0u8; 32];
+    transport.export_keying_material(PSK_LABEL, id, &mut psk)?;
+    Ok(psk)
+}
+
+// What the initiator keeps from a session to resume it later.
+#[derive(Clone)]
+pub struct ResumptionTicket {
+    ticket: [u8; TICKET_LEN],
+    psk: [u8; 32],
+    remote_key: [u8; 32],
+}
+
+impl ResumptionTicket {
+    // `ticket` as received from the responder over `transport`.
+    pub fn new(transport: &Transport, ticket: &[u8]) -> Result<Self, Error> {
+        let ticket: [u8; TICKET_LEN] = ticket.try_into().map_err(|_| Error::Input)?;
+        Ok(Self {
+            psk: derive_psk(transport, &ticket[..8])?,
+            ticket,
+            remote_key: transport.remote_key().into(),
+        })
+    }
+}
+
+// Responder side: issues tickets and accepts each of them once. Redeemed
+// ticket ids are remembered until their tickets expire. With more than `N`
+// unexpired redemptions, the oldest id is forgotten and every ticket up to
+// it is refused from then on, so a ticket is never accepted twice.
+pub struct TicketIssuer<const N: usize> {
+    key: CipherState,
+    next_id: u64,
+    lifetime: u64,
+    redeemed: [Option<(u64, u64)>; N],
+    // ids up to and including this one are refused
+    floor: Option<u64>,
+    observer: Option<&'static dyn Observer>,
+}
+
+impl<const N: usize> TicketIssuer<N> {
+    // `key` encrypts the tickets. Ticket ids are the nonces, so `first_id`
+    // must be above any id ever issued with the same key, e.g. from a
+    // persisted counter. Times are in the caller's ticks.
+    pub fn new(key: [u8; 32], first_id: u64, lifetime: u64) -> Self {
+        Self {
+            key: CipherState::new(key),
+            next_id: first_id,
+            lifetime,
+            redeemed: [None; N],
+            floor: None,
+            observer: None,
+        }
+    }
+    // Set on every resumption accepted from now on, see
+    // `Resumption::set_observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+    // Ticket for the initiator of `transport`, to be sent over it.
+    pub fn issue(
+        &mut self,
+        transport: &Transport,
+        now: u64,
+        ticket: &mut [u8],
+    ) -> Result<usize, Error> {
+        let ticket = ticket.get_mut(..TICKET_LEN).ok_or(Error::Input)?;
+        let id = self.next_id;
+        // u64::MAX is the rekey nonce
+        if id == u64::MAX {
+            return Err(Error::Input);
+        }
+        let (header, body) = ticket.split_at_mut(8);
+        header.copy_from_slice(&id.to_le_bytes());
+        body[..32].copy_from_slice(&derive_psk(transport, header)?);
+        body[32..64].copy_from_slice(transport.remote_key().as_bytes());
+        body[64..72].copy_from_slice(&now.saturating_add(self.lifetime).to_le_bytes());
+        self.key
+            .encrypt_in_place_at(id, header, body, TICKET_PLAIN_LEN)?;
+        self.next_id += 1;
+        Ok(TICKET_LEN)
+    }
+    fn redeem(&mut self, id: u64, not_after: u64, now: u64) -> Result<(), Error> {
+        if self.floor.is_some_and(|floor| id <= floor)
+            || self.redeemed.iter().flatten().any(|(i, _)| *i == id)
+        {
+            return Err(Error::Replay);
+        }
+        let slot = match self
+            .redeemed
+            .iter()
+            .position(|r| r.is_none_or(|(_, not_after)| now > not_after))
+        {
+            Some(i) => i,
+            None => {
+                let (i, oldest) = self
+                    .redeemed
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, r)| r.map(|(id, _)| (i, id)))
+                    .min_by_key(|(_, id)| *id)
+                    .ok_or(Error::QueueFull)?;
+                // the floor never goes down, an id redeemed out of order
+                // may be below it
+                self.floor = self.floor.max(Some(oldest));
+                i
+            }
+        };
+        self.redeemed[slot] = Some((id, not_after));
+        Ok(())
+    }
+    // Reads message 1 of a resumption, returning the responder handshake
+    // and the length of the early data written to `payload`. The ticket is
+    // used up even if the rest of the handshake fails. Early data can be
+    // replayed to another responder sharing the ticket key, so it must be
+    // safe to process twice.
+    pub fn accept(
+        &mut self,
+        e: [u8; DH_LEN],
+        message: &[u8],
+        payload: &mut [u8],
+        now: u64,
+    ) -> Result<(Resumption, usize), Error> {
+        if message.len() < RESUME_MSG1_OVERHEAD {
+            return Err(Error::Input);
+        }
+        let (ticket, rest) = message.split_at(TICKET_LEN);
+        let (header, body) = ticket.split_at(8);
+        let id = u64::from_le_bytes(header.try_into().unwrap());
+        let mut plain = [0u8; TICKET_PLAIN_LEN];
+        self.key.set_nonce(id);
+        self.key.decrypt_with_ad(header, body, &mut plain)?;
+        let not_after = u64::from_le_bytes(plain[64..].try_into().unwrap());
+        if now > not_after {
+            return Err(Error::Expired);
+        }
+        self.redeem(id, not_after, now)?;
+
+        let mut psk = [0u8; 32];
+        psk.copy_from_slice(&plain[..32]);
+        let mut rs = [0u8; 32];
+        rs.copy_from_slice(&plain[32..64]);
+        let mut resumption = Resumption::new(false, e, psk, rs, ticket);
+        resumption.observer = self.observer;
+        let len = resumption.read_message(rest, payload)?;
+        Ok((resumption, len))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    I1,
+    I2,
+    R1,
+    R2,
+    Done,
+}
+
+//
+//  -> psk, e
+//  <- e, ee
+//
+// NNpsk0 with the PSK from a ticket, which is sent in front of message 1
+// and is the prologue. The payload of message 1 is 0-RTT early data. The
+// static keys are those of the session that issued the ticket.
+pub struct Resumption {
+    init: bool,
+    e: [u8; DH_LEN],
+    re: [u8; DH_LEN],
+    rs: [u8; DH_LEN],
+    state: State,
+    sym: SymmetricState,
+    ticket: [u8; TICKET_LEN],
+    observer: Option<&'static dyn Observer>,
+}
+
+impl Resumption {
+    fn new(init: bool, e: [u8; DH_LEN], psk: [u8; 32], rs: [u8; 32], ticket: &[u8]) -> Self {
+        let mut sym = SymmetricState::with_protocol_name(PROT_NAME);
+        sym.mix_hash(ticket);
+        sym.mix_key_and_hash(&psk);
+        Self {
+            init,
+            e,
+            re: [0; DH_LEN],
+            rs,
+            state: if init { State::I1 } else { State::R1 },
+            sym,
+            ticket: ticket.try_into().unwrap(),
+            observer: None,
+        }
+    }
+    pub fn init(e: [u8; DH_LEN], ticket: &ResumptionTicket) -> Self {
+        Self::new(true, e, ticket.psk, ticket.remote_key, &ticket.ticket)
+    }
+    // Reports this resumption's events and, after `upgrade`, the
+    // transport's.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+    fn next_state(&mut self, next: State) {
+        let message = match self.state {
+            State::I1 | State::R1 => 1,
+            _ => 2,
+        };
+        self.state = next;
+        let initiator = self.init;
+        observe(self.observer, Event::Resumption { initiator, message });
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+    pub fn remote_key(&self) -> PublicKey {
+        PublicKey(self.rs)
+    }
+    pub fn upgrade(self) -> Result<Transport, Error> {
+        if !self.is_finished() {
+            return Err(Error::NotMyTurn);
+        }
+        let ex = self.sym.exporter_secret();
+        let (pad_i, pad_r) = self.sym.padding_keys();
+        let (c1, c2) = self.sym.split();
+        let (send, recv, pad_key) = if self.init {
+            (c1, c2, pad_i)
+        } else {
+            (c2, c1, pad_r)
+        };
+        Ok(Transport {
+            rs: self.rs,
+            ex,
+            padding: Default::default(),
+            pad_key,
+            observer: self.observer,
+            send,
+            recv,
+        })
+    }
+    // Message 1 is the ticket, then e and the early data.
+    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        let (ticket_len, next) = match self.state {
+            State::I1 => (TICKET_LEN, State::I2),
+            State::R2 => (0, State::Done),
+            State::I2 | State::R1 => return Err(Error::NotMyTurn),
+            State::Done => return Err(Error::NeedUpgrade),
+        };
+        if message.len() < ticket_len + DH_LEN + payload.len() + TAG_LEN {
+            return Err(Error::Input);
+        }
+        let (msg_ticket, rest) = message.split_at_mut(ticket_len);
+        let (msg_e, msg_p) = rest.split_at_mut(DH_LEN);
+        msg_ticket.copy_from_slice(&self.ticket[..ticket_len]);
+
+        let prev_sym = self.sym.clone();
+        // e, with MixKey as psk handshakes require
+        let e = pub_key(self.e);
+        msg_e.copy_from_slice(&e);
+        self.sym.mix_hash(&e);
+        self.sym.mix_key(&e);
+
+        let result = match self.state {
+            // ee
+            State::R2 => x25519(self.e, self.re).map(|ee| self.sym.mix_key(&ee)),
+            _ => Ok(()),
+        }
+        // payload
+        .and_then(|_| self.sym.encrypt_and_hash(payload, msg_p));
+        observe_result(self.observer, &result);
+        match result {
+            Ok(len) => {
+                self.next_state(next);
+                Ok(ticket_len + DH_LEN + len)
+            }
+            Err(e) => {
+                self.sym = prev_sym;
+                Err(e)
+            }
+        }
+    }
+    // On the responder message 1 is read by `TicketIssuer::accept`.
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        let next = match self.state {
+            State::R1 => State::R2,
+            State::I2 => State::Done,
+            State::I1 | State::R2 => return Err(Error::NotMyTurn),
+            State::Done => return Err(Error::NeedUpgrade),
+        };
+        if message.len() < DH_LEN + TAG_LEN || payload.len() < message.len() - DH_LEN - TAG_LEN {
+            return Err(Error::Input);
+        }
+        let (msg_e, msg_p) = message.split_at(DH_LEN);
+
+        let prev_sym = self.sym.clone();
+        // e
+        self.re.copy_from_slice(msg_e);
+        self.sym.mix_hash(&self.re);
+        self.sym.mix_key(&self.re);
+
+        let result = match self.state {
+            // ee
+            State::I2 => x25519(self.e, self.re).map(|ee| self.sym.mix_key(&ee)),
+            _ => Ok(()),
+        }
+        // payload
+        .and_then(|_| self.sym.decrypt_and_hash(msg_p, payload));
+        observe_result(self.observer, &result);
+        if result.is_ok() {
+            self.next_state(next);
+        } else {
+            self.sym = prev_sym;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PROT_NAME;
+    use crate::test_util::*;
+    use crate::*;
+
+    // full handshake, then a ticket from the responder
+    fn session<const N: usize>(
+        issuer: &mut TicketIssuer<N>,
+        now: u64,
+    ) -> (ResumptionTicket, [u8; TICKET_LEN]) {
+        let mut buf_init = [0u8; 200];
+        let mut buf_resp = [0u8; 200];
+        let (mut init, mut resp) = transport_pair();
+
+        let mut ticket = [0u8; TICKET_LEN];
+        issuer.issue(&resp, now, &mut ticket).unwrap();
+        let len = resp.write_message(&ticket, &mut buf_resp).unwrap();
+        let len = init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        (
+            ResumptionTicket::new(&init, &buf_init[..len]).unwrap(),
+            ticket,
+        )
+    }
+
+    #[test]
+    fn test_resumption() {
+        let mut issuer = TicketIssuer::<4>::new([7u8; 32], 0, 100);
+        let (ticket, _) = session(&mut issuer, 0);
+        let mut msg1 = [0u8; 300];
+        let mut buf_init = [0u8; 300];
+        let mut buf_resp = [0u8; 300];
+
+        let mut init = Resumption::init([4u8; 32], &ticket);
+        let len = init.write_message(b"early", &mut msg1).unwrap();
+        assert_eq!(len, RESUME_MSG1_OVERHEAD + 5);
+        let (mut resp, early) = issuer
+            .accept([5u8; 32], &msg1[..len], &mut buf_resp, 10)
+            .unwrap();
+        assert_eq!(&buf_resp[..early], b"early");
+        assert_eq!(resp.remote_key(), [1u8; 32].public_key());
+
+        let len2 = resp.write_message(b"ok", &mut buf_resp).unwrap();
+        let n = init.read_message(&buf_resp[..len2], &mut buf_init).unwrap();
+        assert_eq!(&buf_init[..n], b"ok");
+        assert_eq!(init.remote_key(), [3u8; 32].public_key());
+
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let n = init.write_message(b"data", &mut buf_init).unwrap();
+        let n = resp.read_message(&buf_init[..n], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..n], b"data");
+
+        // single use
+        assert!(matches!(
+            issuer.accept([5u8; 32], &msg1[..len], &mut buf_resp, 10),
+            Err(Error::Replay)
+        ));
+    }
+
+    #[test]
+    fn test_ticket_expiry_and_replay_limit() {
+        let mut issuer = TicketIssuer::<1>::new([7u8; 32], 0, 100);
+        let mut msg1 = [0u8; 300];
+        let mut payload = [0u8; 300];
+        let mut accept = |issuer: &mut TicketIssuer<1>, ticket, now| {
+            let len = Resumption::init([4u8; 32], ticket)
+                .write_message(&[], &mut msg1)
+                .unwrap();
+            issuer
+                .accept([5u8; 32], &msg1[..len], &mut payload, now)
+                .map(|_| ())
+        };
+
+        let (a, _) = session(&mut issuer, 0);
+        let (b, _) = session(&mut issuer, 0);
+        let (c, _) = session(&mut issuer, 0);
+        assert!(matches!(accept(&mut issuer, &a, 101), Err(Error::Expired)));
+        accept(&mut issuer, &b, 50).unwrap();
+        // b fills the only slot and is forgotten for c, so a and b are
+        // refused from then on
+        accept(&mut issuer, &c, 50).unwrap();
+        assert!(matches!(accept(&mut issuer, &a, 50), Err(Error::Replay)));
+        assert!(matches!(accept(&mut issuer, &b, 50), Err(Error::Replay)));
+
+        // forged ticket
+        let mut forged = c.clone();
+        forged.ticket[20] ^= 1;
+        assert!(matches!(
+            accept(&mut issuer, &forged, 50),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn test_replay_limit_out_of_order() {
+        let mut issuer = TicketIssuer::<1>::new([7u8; 32], 0, 100);
+        let mut msg1 = [0u8; 300];
+        let mut payload = [0u8; 300];
+        let mut accept = |issuer: &mut TicketIssuer<1>, ticket, now| {
+            let len = Resumption::init([4u8; 32], ticket)
+                .write_message(&[], &mut msg1)
+                .unwrap();
+            issuer
+                .accept([5u8; 32], &msg1[..len], &mut payload, now)
+                .map(|_| ())
+        };
+
+        let (a, _) = session(&mut issuer, 0);
+        let (b, _) = session(&mut issuer, 0);
+        let (c, _) = session(&mut issuer, 0);
+        accept(&mut issuer, &b, 50).unwrap();
+        // a is below b but was never redeemed, forgetting b refuses up to b
+        accept(&mut issuer, &a, 50).unwrap();
+        // forgetting a must not lower that
+        accept(&mut issuer, &c, 50).unwrap();
+        assert!(matches!(accept(&mut issuer, &b, 50), Err(Error::Replay)));
+        assert!(matches!(accept(&mut issuer, &a, 50), Err(Error::Replay)));
+    }
+
+    #[test]
+    fn test_resumption_using_snow() {
+        let mut issuer = TicketIssuer::<4>::new([7u8; 32], 0, 100);
+        let (ticket, wire) = session(&mut issuer, 0);
+        let mut msg = [0u8; 300];
+        let mut payload = [0u8; 300];
+
+        let mut init =
+            snow::Builder::new(core::str::from_utf8(PROT_NAME).unwrap().parse().unwrap())
+                .prologue(&wire)
+                .psk(0, &ticket.psk)
+                .build_initiator()
+                .unwrap();
+        msg[..TICKET_LEN].copy_from_slice(&wire);
+        let len = init
+            .write_message(b"early", &mut msg[TICKET_LEN..])
+            .unwrap();
+        let (mut resp, early) = issuer
+            .accept([5u8; 32], &msg[..TICKET_LEN + len], &mut payload, 1)
+            .unwrap();
+        assert_eq!(&payload[..early], b"early");
+
+        let len = resp.write_message(b"ok", &mut msg).unwrap();
+        let len = init.read_message(&msg[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"ok");
+
+        let mut init = init.into_transport_mode().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"hello", &mut msg).unwrap();
+        let len = resp.read_message(&msg[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"hello");
+    }
+}
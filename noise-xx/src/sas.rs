@@ -0,0 +1,173 @@
+use hkdf::Hkdf;
+
+use crate::Error;
+
+const SAS_INFO: &[u8] = b"liot sas";
+const COMMIT_INFO: &[u8] = b"liot sas commit";
+
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+// Short authentication string derived from a completed handshake hash.
+// Both sides show it and the user confirms they match.
+pub struct Sas([u8; 8]);
+
+impl Sas {
+    pub fn new(h: &[u8; 32]) -> Self {
+        Self::derive(h, &[])
+    }
+    fn derive(h: &[u8; 32], nonces: &[u8]) -> Self {
+        let hkdf = Hkdf::<blake2::Blake2s>::new(Some(h), nonces);
+        let mut output = [0u8; 8];
+        hkdf.expand(SAS_INFO, &mut output).unwrap();
+        Self(output)
+    }
+    // 6 decimal digits, 000000..=999999
+    pub fn numeric(&self) -> u32 {
+        (u64::from_be_bytes(self.0) % 1_000_000) as u32
+    }
+    fn indices(&self) -> [usize; 7] {
+        let bits = u64::from_be_bytes(self.0);
+        let mut out = [0; 7];
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = ((bits >> (58 - 6 * i)) & 0x3f) as usize;
+        }
+        out
+    }
+    pub fn emoji(&self) -> [&'static str; 7] {
+        self.indices().map(|i| EMOJI[i].0)
+    }
+    pub fn words(&self) -> [&'static str; 7] {
+        self.indices().map(|i| EMOJI[i].1)
+    }
+}
+
+fn commitment(h: &[u8; 32], nonce: &[u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<blake2::Blake2s>::new(Some(h), nonce);
+    let mut output = [0u8; 32];
+    hkdf.expand(COMMIT_INFO, &mut output).unwrap();
+    output
+}
+
+fn derive_with_nonces(h: &[u8; 32], nonce_i: &[u8; 32], nonce_r: &[u8; 32]) -> Sas {
+    let mut nonces = [0u8; 64];
+    nonces[..32].copy_from_slice(nonce_i);
+    nonces[32..].copy_from_slice(nonce_r);
+    Sas::derive(h, &nonces)
+}
+
+// Commit-reveal exchange run over the transport after the handshake, so a
+// MITM has to pick its keys before learning the nonces and gets a single
+// guess at the short code:
+//
+//  -> commitment(h, nonce_i)
+//  <- nonce_r
+//  -> nonce_i
+pub struct SasInitiator {
+    h: [u8; 32],
+    nonce: [u8; 32],
+}
+
+pub struct SasResponder {
+    h: [u8; 32],
+    nonce: [u8; 32],
+    commitment: [u8; 32],
+}
+
+impl SasInitiator {
+    pub fn new(h: [u8; 32], nonce: [u8; 32]) -> Self {
+        Self { h, nonce }
+    }
+    pub fn commitment(&self) -> [u8; 32] {
+        commitment(&self.h, &self.nonce)
+    }
+    pub fn reveal(self, nonce_r: &[u8; 32]) -> ([u8; 32], Sas) {
+        (
+            self.nonce,
+            derive_with_nonces(&self.h, &self.nonce, nonce_r),
+        )
+    }
+}
+
+impl SasResponder {
+    pub fn new(h: [u8; 32], nonce: [u8; 32], commitment: [u8; 32]) -> Self {
+        Self {
+            h,
+            nonce,
+            commitment,
+        }
+    }
+    pub fn nonce(&self) -> [u8; 32] {
+        self.nonce
+    }
+    pub fn verify(self, nonce_i: &[u8; 32]) -> Result<Sas, Error> {
+        if commitment(&self.h, nonce_i) != self.commitment {
+            return Err(Error::Commitment);
+        }
+        Ok(derive_with_nonces(&self.h, nonce_i, &self.nonce))
+    }
+}
@@ -0,0 +1,462 @@
+use embedded_io::{ErrorKind, Read, Write};
+
+use crate::{Error, Handshake, Transport};
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+pub const CRC_LEN: usize = 2;
+// transport frames start with the sender's nonce
+pub const SERIAL_NONCE_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    // frames end with 0x00, which never appears inside a frame
+    Cobs,
+    // RFC 1055, frames end with 0xc0
+    Slip,
+}
+
+impl Framing {
+    fn delimiter(self) -> u8 {
+        match self {
+            Self::Cobs => 0,
+            Self::Slip => SLIP_END,
+        }
+    }
+}
+
+// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Encodes `input` without the trailing delimiter.
+pub fn cobs_encode(input: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    let mut code_at = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for b in input {
+        if *b == 0 {
+            *out.get_mut(code_at).ok_or(Error::Input)? = code;
+            code_at = len;
+            len += 1;
+            code = 1;
+        } else {
+            *out.get_mut(len).ok_or(Error::Input)? = *b;
+            len += 1;
+            code += 1;
+            if code == 0xff {
+                *out.get_mut(code_at).ok_or(Error::Input)? = code;
+                code_at = len;
+                len += 1;
+                code = 1;
+            }
+        }
+    }
+    *out.get_mut(code_at).ok_or(Error::Input)? = code;
+    Ok(len)
+}
+
+// Decodes in place, the output is never longer than the input.
+pub fn cobs_decode(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        if code == 0 || read + code > buf.len() {
+            return Err(Error::Input);
+        }
+        read += 1;
+        for _ in 1..code {
+            buf[write] = buf[read];
+            write += 1;
+            read += 1;
+        }
+        if code != 0xff && read != buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+// Encodes `input` without the trailing delimiter.
+pub fn slip_encode(input: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    for b in input {
+        let escaped: &[u8] = match *b {
+            SLIP_END => &[SLIP_ESC, SLIP_ESC_END],
+            SLIP_ESC => &[SLIP_ESC, SLIP_ESC_ESC],
+            _ => core::slice::from_ref(b),
+        };
+        out.get_mut(len..len + escaped.len())
+            .ok_or(Error::Input)?
+            .copy_from_slice(escaped);
+        len += escaped.len();
+    }
+    Ok(len)
+}
+
+// Decodes in place, the output is never longer than the input.
+pub fn slip_decode(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        buf[write] = match buf[read] {
+            SLIP_ESC => {
+                read += 1;
+                match buf.get(read) {
+                    Some(&SLIP_ESC_END) => SLIP_END,
+                    Some(&SLIP_ESC_ESC) => SLIP_ESC,
+                    _ => return Err(Error::Input),
+                }
+            }
+            b => b,
+        };
+        read += 1;
+        write += 1;
+    }
+    Ok(write)
+}
+
+fn io_error<E: embedded_io::Error>(e: E) -> Error {
+    Error::Serial(e.kind())
+}
+
+// Frames Noise messages over a byte stream such as a UART. Each frame is
+// the message followed by its CRC, encoded and enclosed in framing
+// delimiters. `N` bounds the encoded frame size. Frames that are too long or
+// fail to decode or checksum are dropped and reading resumes at the next
+// delimiter, so the link resynchronizes after line noise, and the leading
+// delimiter keeps noise from sticking to the start of the next frame.
+// Transport frames carry their nonce, so the messages after a dropped one
+// still decrypt.
+pub struct SerialLink<T, const N: usize> {
+    io: T,
+    framing: Framing,
+    rx: [u8; N],
+    rx_len: usize,
+    overflow: bool,
+    chunk: [u8; 32],
+    chunk_start: usize,
+    chunk_end: usize,
+}
+
+impl<T: Read + Write, const N: usize> SerialLink<T, N> {
+    pub fn new(io: T, framing: Framing) -> Self {
+        Self {
+            io,
+            framing,
+            rx: [0; N],
+            rx_len: 0,
+            overflow: false,
+            chunk: [0; 32],
+            chunk_start: 0,
+            chunk_end: 0,
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let mut raw = [0u8; N];
+        let raw = raw.get_mut(..frame.len() + CRC_LEN).ok_or(Error::Input)?;
+        raw[..frame.len()].copy_from_slice(frame);
+        raw[frame.len()..].copy_from_slice(&crc16(frame).to_le_bytes());
+
+        // the encoded frame between two delimiters
+        let mut tx = [0u8; N];
+        let (start, encoded) = tx.split_at_mut(1);
+        let len = match self.framing {
+            Framing::Cobs => cobs_encode(raw, encoded)?,
+            Framing::Slip => slip_encode(raw, encoded)?,
+        };
+        if len + 1 == N {
+            return Err(Error::Input);
+        }
+        start[0] = self.framing.delimiter();
+        encoded[len] = self.framing.delimiter();
+        self.io.write_all(&tx[..len + 2]).map_err(io_error)?;
+        self.io.flush().map_err(io_error)
+    }
+    // Blocks until a valid frame has arrived and copies it into `frame`.
+    pub fn read_frame(&mut self, frame: &mut [u8]) -> Result<usize, Error> {
+        let delimiter = self.framing.delimiter();
+        loop {
+            while self.chunk_start < self.chunk_end {
+                let b = self.chunk[self.chunk_start];
+                self.chunk_start += 1;
+                if b != delimiter {
+                    if self.rx_len < N {
+                        self.rx[self.rx_len] = b;
+                        self.rx_len += 1;
+                    } else {
+                        self.overflow = true;
+                    }
+                    continue;
+                }
+                let len = self.rx_len;
+                let overflow = self.overflow;
+                self.rx_len = 0;
+                self.overflow = false;
+                if len == 0 {
+                    continue;
+                }
+                if let Some(len) = self.decode(len).filter(|_| !overflow) {
+                    let out = frame.get_mut(..len).ok_or(Error::Input)?;
+                    out.copy_from_slice(&self.rx[..len]);
+                    return Ok(len);
+                }
+            }
+            let n = self.io.read(&mut self.chunk).map_err(io_error)?;
+            if n == 0 {
+                return Err(Error::Serial(ErrorKind::BrokenPipe));
+            }
+            self.chunk_start = 0;
+            self.chunk_end = n;
+        }
+    }
+    fn decode(&mut self, len: usize) -> Option<usize> {
+        let raw = &mut self.rx[..len];
+        let len = match self.framing {
+            Framing::Cobs => cobs_decode(raw).ok()?,
+            Framing::Slip => slip_decode(raw).ok()?,
+        };
+        let len = len.checked_sub(CRC_LEN)?;
+        let crc = u16::from_le_bytes([self.rx[len], self.rx[len + 1]]);
+        if crc16(&self.rx[..len]) != crc {
+            return None;
+        }
+        Some(len)
+    }
+    // Runs the handshake to completion with empty payloads, skipping noise
+    // and damaged frames between the handshake messages. There are no
+    // retransmissions, so if a handshake message itself is lost both sides
+    // wait until `T` reports an error, e.g. a read timeout, and a fresh
+    // handshake is needed.
+    pub fn handshake(&mut self, mut handshake: Handshake) -> Result<Transport, Error> {
+        let mut message = [0u8; N];
+        let mut payload = [0u8; N];
+        while !handshake.is_finished() {
+            if handshake.is_my_turn() {
+                let len = handshake.write_message(&[], &mut message)?;
+                self.write_frame(&message[..len])?;
+            } else {
+                let len = self.read_frame(&mut message)?;
+                handshake.read_message(&message[..len], &mut payload)?;
+            }
+        }
+        handshake.upgrade()
+    }
+    pub fn write_message(
+        &mut self,
+        transport: &mut Transport,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut message = [0u8; N];
+        let (nonce, rest) = message.split_at_mut(SERIAL_NONCE_LEN);
+        nonce.copy_from_slice(&transport.send_nonce().to_le_bytes());
+        let len = transport.write_message(payload, rest)?;
+        self.write_frame(&message[..SERIAL_NONCE_LEN + len])
+    }
+    // Messages lost on the line are skipped, replayed or reordered ones fail
+    // with `Error::Replay`.
+    pub fn read_message(
+        &mut self,
+        transport: &mut Transport,
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut message = [0u8; N];
+        let len = self.read_frame(&mut message)?;
+        let (nonce, message) = message[..len]
+            .split_at_checked(SERIAL_NONCE_LEN)
+            .ok_or(Error::Input)?;
+        let nonce = u64::from_le_bytes(nonce.try_into().unwrap());
+        if nonce < transport.recv_nonce() {
+            return Err(Error::Replay);
+        }
+        let prev = transport.recv_nonce();
+        transport.set_receive_nonce(nonce);
+        transport
+            .read_message(message, payload)
+            .inspect_err(|_| transport.set_receive_nonce(prev))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::vec::Vec;
+
+    extern crate std;
+
+    struct Pipe {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        buf: Vec<u8>,
+        // flips a byte in the n-th write to simulate line noise
+        noise: Option<usize>,
+        // sent before every write, noise between frames
+        garbage: &'static [u8],
+        writes: usize,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        let end = |tx, rx| Pipe {
+            tx,
+            rx,
+            buf: Vec::new(),
+            noise: None,
+            garbage: &[],
+            writes: 0,
+        };
+        (end(tx_a, rx_b), end(tx_b, rx_a))
+    }
+
+    impl embedded_io::ErrorType for Pipe {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    impl embedded_io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.buf.is_empty() {
+                match self.rx.recv() {
+                    Ok(data) => self.buf = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.buf.len());
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl embedded_io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let mut data = buf.to_vec();
+            self.writes += 1;
+            if self.noise == Some(self.writes) {
+                data[1] ^= 0x55;
+            }
+            if !self.garbage.is_empty() {
+                self.tx
+                    .send(self.garbage.to_vec())
+                    .map_err(|_| embedded_io::ErrorKind::BrokenPipe)?;
+            }
+            self.tx
+                .send(data)
+                .map_err(|_| embedded_io::ErrorKind::BrokenPipe)?;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cobs_slip_roundtrip() {
+        let mut data = [0u8; 600];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 7) as u8 * 0x20;
+        }
+        let mut encoded = [0u8; 700];
+        for input in [&data[..0], &data[..1], &data[1..255], &data[1..], &data[..]] {
+            let len = cobs_encode(input, &mut encoded).unwrap();
+            assert!(!encoded[..len].contains(&0));
+            let len = cobs_decode(&mut encoded[..len]).unwrap();
+            assert_eq!(&encoded[..len], input);
+        }
+        // runs of 254 and more non-zero bytes take a 0xff code byte
+        let mut data = [0x11u8; 600];
+        data[254] = 0;
+        for input in [&data[..254], &data[..255], &data[255..], &data[..]] {
+            let len = cobs_encode(input, &mut encoded).unwrap();
+            assert!(!encoded[..len].contains(&0));
+            assert_eq!(encoded[0], if input[0] == 0 { 1 } else { 0xff });
+            let len = cobs_decode(&mut encoded[..len]).unwrap();
+            assert_eq!(&encoded[..len], input);
+        }
+        let input = [0xc0, 1, 0xdb, 0xdc, 0];
+        let len = slip_encode(&input, &mut encoded).unwrap();
+        assert!(!encoded[..len].contains(&0xc0));
+        let len = slip_decode(&mut encoded[..len]).unwrap();
+        assert_eq!(&encoded[..len], &input);
+    }
+
+    fn run_link(framing: Framing) {
+        let (a, mut b) = pipe();
+        b.noise = Some(2);
+        let (mut transport, mut resp_transport) = transport_pair();
+        let resp = std::thread::spawn(move || {
+            let mut link = SerialLink::<_, 256>::new(a, framing);
+            let mut payload = [0u8; 256];
+            let mut received = Vec::new();
+            // the second frame from the initiator is corrupted and dropped
+            while let Ok(len) = link.read_message(&mut resp_transport, &mut payload) {
+                received.push(payload[..len].to_vec());
+                if received.len() == 2 {
+                    break;
+                }
+            }
+            received
+        });
+        let mut link = SerialLink::<_, 256>::new(b, framing);
+        link.write_message(&mut transport, &[0xc0, 0, 1]).unwrap();
+        // corrupted on the wire
+        link.write_message(&mut transport, b"lost").unwrap();
+        link.write_message(&mut transport, b"after noise").unwrap();
+        let received = resp.join().unwrap();
+        assert_eq!(received, [&[0xc0, 0, 1][..], b"after noise"]);
+    }
+
+    #[test]
+    fn test_serial_link() {
+        run_link(Framing::Cobs);
+        run_link(Framing::Slip);
+    }
+
+    fn run_handshake(framing: Framing) {
+        let (mut a, mut b) = pipe();
+        // noise with and without delimiters between all frames
+        a.garbage = &[0x13, 0x00, 0x37, 0xdb];
+        b.garbage = &[0xc0, 0xdb, 0x42, 0x00, 0x01];
+        let (init, resp) = handshake_pair();
+        let resp = std::thread::spawn(move || {
+            let mut link = SerialLink::<_, 256>::new(a, framing);
+            let mut transport = link.handshake(resp).unwrap();
+            let mut payload = [0u8; 256];
+            let len = link.read_message(&mut transport, &mut payload).unwrap();
+            payload[..len].to_vec()
+        });
+        let mut link = SerialLink::<_, 256>::new(b, framing);
+        let mut transport = link.handshake(init).unwrap();
+        link.write_message(&mut transport, b"through noise")
+            .unwrap();
+        assert_eq!(resp.join().unwrap(), b"through noise");
+    }
+
+    #[test]
+    fn test_serial_handshake_noise() {
+        run_handshake(Framing::Cobs);
+        run_handshake(Framing::Slip);
+    }
+}
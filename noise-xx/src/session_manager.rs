@@ -0,0 +1,409 @@
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::{Error, Handshake, PublicKey, Transport};
+
+//  1: type, sender index                  -> e
+//  2: type, sender index, receiver index  <- e, ee, s, es
+//  3: type, receiver index                -> s, se
+//  4: type, receiver index, nonce         transport message
+pub const MSG_INIT: u8 = 1;
+pub const MSG_RESP: u8 = 2;
+pub const MSG_FINISH: u8 = 3;
+pub const MSG_DATA: u8 = 4;
+
+pub const INIT_HEADER_LEN: usize = 5;
+pub const RESP_HEADER_LEN: usize = 9;
+pub const FINISH_HEADER_LEN: usize = 5;
+pub const DATA_HEADER_LEN: usize = 13;
+
+// Default limit on half-open handshakes, see `SessionManager::set_max_pending`.
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(b)
+}
+
+struct Pending {
+    handshake: Handshake,
+    peer_index: u32,
+    started: Instant,
+}
+
+struct Session {
+    transport: Transport,
+    peer_index: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    // send out[..len] back to the peer
+    Reply(usize),
+    // handshake finished, the message 3 payload is in out[..len]
+    Established {
+        index: u32,
+        remote_key: PublicKey,
+        len: usize,
+    },
+    // transport payload in out[..len]
+    Data {
+        index: u32,
+        len: usize,
+    },
+}
+
+// Responder side of many concurrent sessions. Every handshake gets a local
+// session index which the peer echoes back, so datagrams can be routed
+// without trial decryption.
+pub struct SessionManager {
+    s: [u8; 32],
+    prologue: Vec<u8>,
+    ephemeral: Box<dyn FnMut() -> [u8; 32] + Send>,
+    timeout: Duration,
+    max_pending: usize,
+    next_index: u32,
+    pending: HashMap<u32, Pending>,
+    sessions: HashMap<u32, Session>,
+    by_key: HashMap<PublicKey, u32>,
+}
+
+impl SessionManager {
+    pub fn new(
+        s: [u8; 32],
+        prologue: &[u8],
+        ephemeral: Box<dyn FnMut() -> [u8; 32] + Send>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            s,
+            prologue: prologue.to_vec(),
+            ephemeral,
+            timeout,
+            max_pending: DEFAULT_MAX_PENDING,
+            next_index: 0,
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+            by_key: HashMap::new(),
+        }
+    }
+    fn allocate_index(&mut self) -> u32 {
+        loop {
+            let index = self.next_index;
+            self.next_index = self.next_index.wrapping_add(1);
+            if !self.pending.contains_key(&index) && !self.sessions.contains_key(&index) {
+                return index;
+            }
+        }
+    }
+    // Message 1 is unauthenticated, so every one that arrives while `max`
+    // handshakes are half-open fails with `Error::QueueFull` instead of
+    // costing a key exchange and memory.
+    pub fn set_max_pending(&mut self, max: usize) {
+        self.max_pending = max;
+    }
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn session_len(&self) -> usize {
+        self.sessions.len()
+    }
+    pub fn remote_key(&self, index: u32) -> Option<PublicKey> {
+        self.sessions.get(&index).map(|s| s.transport.remote_key())
+    }
+    pub fn remove(&mut self, index: u32) {
+        if let Some(session) = self.sessions.remove(&index) {
+            self.by_key.remove(&session.transport.remote_key());
+        }
+        self.pending.remove(&index);
+    }
+    // Drops half-open handshakes older than the timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, p| now.saturating_duration_since(p.started) < timeout);
+    }
+    pub fn handle(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+        out: &mut [u8],
+    ) -> Result<SessionEvent, Error> {
+        match datagram.first() {
+            Some(&MSG_INIT) if datagram.len() >= INIT_HEADER_LEN => {
+                self.handle_init(datagram, now, out)
+            }
+            Some(&MSG_FINISH) if datagram.len() >= FINISH_HEADER_LEN => {
+                self.handle_finish(datagram, now, out)
+            }
+            Some(&MSG_DATA) if datagram.len() >= DATA_HEADER_LEN => self.handle_data(datagram, out),
+            _ => Err(Error::Input),
+        }
+    }
+    fn handle_init(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+        out: &mut [u8],
+    ) -> Result<SessionEvent, Error> {
+        if out.len() < RESP_HEADER_LEN {
+            return Err(Error::Input);
+        }
+        self.expire(now);
+        if self.pending.len() >= self.max_pending {
+            return Err(Error::QueueFull);
+        }
+        let peer_index = read_u32(&datagram[1..]);
+        let mut handshake = Handshake::resp((self.ephemeral)(), self.s, &self.prologue);
+        let mut payload = [0u8; 0];
+        let message = &datagram[INIT_HEADER_LEN..];
+        if message.len() != 32 {
+            // message 1 payloads are not authenticated, the manager rejects them
+            return Err(Error::Input);
+        }
+        handshake.read_message(message, &mut payload)?;
+
+        let index = self.allocate_index();
+        let (header, rest) = out.split_at_mut(RESP_HEADER_LEN);
+        let len = handshake.write_message(&[], rest)?;
+        header[0] = MSG_RESP;
+        header[1..5].copy_from_slice(&index.to_le_bytes());
+        header[5..9].copy_from_slice(&peer_index.to_le_bytes());
+
+        self.pending.insert(
+            index,
+            Pending {
+                handshake,
+                peer_index,
+                started: now,
+            },
+        );
+        Ok(SessionEvent::Reply(RESP_HEADER_LEN + len))
+    }
+    fn handle_finish(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+        out: &mut [u8],
+    ) -> Result<SessionEvent, Error> {
+        self.expire(now);
+        let index = read_u32(&datagram[1..]);
+        let mut pending = self.pending.remove(&index).ok_or(Error::Input)?;
+        let len = match pending
+            .handshake
+            .read_message(&datagram[FINISH_HEADER_LEN..], out)
+        {
+            Ok(len) => len,
+            Err(e) => {
+                self.pending.insert(index, pending);
+                return Err(e);
+            }
+        };
+        let transport = pending.handshake.upgrade()?;
+        let remote_key = transport.remote_key();
+        if let Some(old) = self.by_key.insert(remote_key, index) {
+            self.sessions.remove(&old);
+        }
+        self.sessions.insert(
+            index,
+            Session {
+                transport,
+                peer_index: pending.peer_index,
+            },
+        );
+        Ok(SessionEvent::Established {
+            index,
+            remote_key,
+            len,
+        })
+    }
+    fn handle_data(&mut self, datagram: &[u8], out: &mut [u8]) -> Result<SessionEvent, Error> {
+        let index = read_u32(&datagram[1..]);
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&datagram[5..DATA_HEADER_LEN]);
+        let nonce = u64::from_le_bytes(nonce);
+
+        let session = self.sessions.get_mut(&index).ok_or(Error::Input)?;
+        let transport = &mut session.transport;
+        // datagrams may be lost but never replayed
+        if nonce < transport.recv_nonce() {
+            return Err(Error::Replay);
+        }
+        let prev = transport.recv_nonce();
+        transport.set_receive_nonce(nonce);
+        let len = transport
+            .read_message(&datagram[DATA_HEADER_LEN..], out)
+            .inspect_err(|_| transport.set_receive_nonce(prev))?;
+        Ok(SessionEvent::Data { index, len })
+    }
+    pub fn write_message(
+        &mut self,
+        index: u32,
+        payload: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        if out.len() < DATA_HEADER_LEN {
+            return Err(Error::Input);
+        }
+        let session = self.sessions.get_mut(&index).ok_or(Error::Input)?;
+        let (header, rest) = out.split_at_mut(DATA_HEADER_LEN);
+        let nonce = session.transport.send_nonce();
+        let len = session.transport.write_message(payload, rest)?;
+        header[0] = MSG_DATA;
+        header[1..5].copy_from_slice(&session.peer_index.to_le_bytes());
+        header[5..13].copy_from_slice(&nonce.to_le_bytes());
+        Ok(DATA_HEADER_LEN + len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use std::boxed::Box;
+    use std::time::{Duration, Instant};
+
+    fn manager() -> SessionManager {
+        let mut e = 0u8;
+        SessionManager::new(
+            [3u8; 32],
+            &[],
+            Box::new(move || {
+                e += 1;
+                [e; 32]
+            }),
+            Duration::from_secs(5),
+        )
+    }
+
+    fn connect(
+        m: &mut SessionManager,
+        s: [u8; 32],
+        local_index: u32,
+        now: Instant,
+    ) -> (u32, u32, Transport) {
+        let mut msg = [0u8; 200];
+        let mut out = [0u8; 200];
+        let mut init = Handshake::init([0u8; 32], s, &[]);
+
+        msg[0] = MSG_INIT;
+        msg[1..5].copy_from_slice(&local_index.to_le_bytes());
+        let len = init
+            .write_message(&[], &mut msg[INIT_HEADER_LEN..])
+            .unwrap();
+        let len = match m.handle(&msg[..INIT_HEADER_LEN + len], now, &mut out) {
+            Ok(SessionEvent::Reply(len)) => len,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(out[0], MSG_RESP);
+        let index = u32::from_le_bytes(out[1..5].try_into().unwrap());
+        assert_eq!(&out[5..9], &local_index.to_le_bytes());
+        init.read_message(&out[RESP_HEADER_LEN..len], &mut msg)
+            .unwrap();
+
+        msg[0] = MSG_FINISH;
+        msg[1..5].copy_from_slice(&index.to_le_bytes());
+        let len = init
+            .write_message(b"hi", &mut msg[FINISH_HEADER_LEN..])
+            .unwrap();
+        match m.handle(&msg[..FINISH_HEADER_LEN + len], now, &mut out) {
+            Ok(SessionEvent::Established {
+                index: i, len: 2, ..
+            }) if i == index => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(&out[..2], b"hi");
+        (index, local_index, init.upgrade().unwrap())
+    }
+
+    #[test]
+    fn test_session_manager() {
+        let now = Instant::now();
+        let mut m = manager();
+        let (a, _, mut ta) = connect(&mut m, [1u8; 32], 100, now);
+        let (b, _, _) = connect(&mut m, [2u8; 32], 200, now);
+        assert_ne!(a, b);
+        assert_eq!(m.session_len(), 2);
+
+        let mut msg = [0u8; 200];
+        let mut out = [0u8; 200];
+        msg[0] = MSG_DATA;
+        msg[1..5].copy_from_slice(&a.to_le_bytes());
+        msg[5..13].copy_from_slice(&ta.send_nonce().to_le_bytes());
+        let len = ta
+            .write_message(b"data", &mut msg[DATA_HEADER_LEN..])
+            .unwrap();
+        assert_eq!(
+            m.handle(&msg[..DATA_HEADER_LEN + len], now, &mut out)
+                .unwrap(),
+            SessionEvent::Data { index: a, len: 4 }
+        );
+        assert_eq!(&out[..4], b"data");
+        assert!(matches!(
+            m.handle(&msg[..DATA_HEADER_LEN + len], now, &mut out),
+            Err(Error::Replay)
+        ));
+
+        let len = m.write_message(a, b"back", &mut msg).unwrap();
+        assert_eq!(&msg[1..5], &100u32.to_le_bytes());
+        let len = ta
+            .read_message(&msg[DATA_HEADER_LEN..len], &mut out)
+            .unwrap();
+        assert_eq!(&out[..len], b"back");
+
+        // same static key reconnects and replaces the old session
+        let (c, _, _) = connect(&mut m, [1u8; 32], 300, now);
+        assert_eq!(m.session_len(), 2);
+        assert!(m.remote_key(a).is_none());
+        assert!(m.remote_key(c).is_some());
+    }
+
+    #[test]
+    fn test_session_manager_expire() {
+        let now = Instant::now();
+        let mut m = manager();
+        let mut msg = [0u8; 200];
+        let mut out = [0u8; 200];
+        let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+        msg[0] = MSG_INIT;
+        let len = init
+            .write_message(&[], &mut msg[INIT_HEADER_LEN..])
+            .unwrap();
+        m.handle(&msg[..INIT_HEADER_LEN + len], now, &mut out)
+            .unwrap();
+        assert_eq!(m.pending_len(), 1);
+        m.expire(now + Duration::from_secs(4));
+        assert_eq!(m.pending_len(), 1);
+        m.expire(now + Duration::from_secs(5));
+        assert_eq!(m.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_session_manager_max_pending() {
+        let now = Instant::now();
+        let mut m = manager();
+        m.set_max_pending(2);
+        let mut msg = [0u8; 200];
+        let mut out = [0u8; 200];
+        let mut init = Handshake::init([0u8; 32], [1u8; 32], &[]);
+        msg[0] = MSG_INIT;
+        let len = init
+            .write_message(&[], &mut msg[INIT_HEADER_LEN..])
+            .unwrap();
+        let msg1 = &msg[..INIT_HEADER_LEN + len];
+        m.handle(msg1, now, &mut out).unwrap();
+        m.handle(msg1, now, &mut out).unwrap();
+        assert!(matches!(
+            m.handle(msg1, now, &mut out),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(m.pending_len(), 2);
+
+        // expired handshakes make room
+        m.handle(msg1, now + Duration::from_secs(5), &mut out)
+            .unwrap();
+        assert_eq!(m.pending_len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    observer::observe, CipherState, Error, Event, NoiseWrite, Observer, Padding, PublicKey,
+};
+
+// Sending half that can be shared between threads, for datagram mode where
+// the receiver takes the nonce from each datagram and calls
+// `set_receive_nonce`. Every message gets a nonce reserved atomically, so
+// threads encrypt in parallel without a lock and never reuse a nonce. Nonces
+// are unique but may go out in any order.
+pub struct SharedSender {
+    send: CipherState,
+    n: AtomicU64,
+    rs: [u8; 32],
+    pad_key: [u8; 32],
+    padding: Padding,
+    observer: Option<&'static dyn Observer>,
+}
+
+impl NoiseWrite {
+    pub fn into_shared(self) -> SharedSender {
+        SharedSender {
+            n: AtomicU64::new(self.send.n),
+            send: self.send,
+            rs: self.rs,
+            pad_key: self.pad_key,
+            padding: self.padding,
+            observer: self.observer,
+        }
+    }
+}
+
+impl SharedSender {
+    pub fn remote_key(&self) -> PublicKey {
+        PublicKey(self.rs)
+    }
+    // The next nonce that will be reserved.
+    pub fn send_nonce(&self) -> u64 {
+        self.n.load(Ordering::Relaxed)
+    }
+    // u64::MAX is reserved for rekeying and never handed out
+    fn reserve(&self) -> Result<u64, Error> {
+        self.n
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < u64::MAX - 1).then_some(n + 1)
+            })
+            .map_err(|_| Error::Input)
+    }
+    // Returns the nonce used and the message length. A nonce reserved for a
+    // message that then fails to encrypt is skipped, never reused.
+    pub fn write_message(&self, payload: &[u8], message: &mut [u8]) -> Result<(u64, usize), Error> {
+        self.write_message_with_ad(&[], payload, message)
+    }
+    pub fn write_message_with_ad(
+        &self,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<(u64, usize), Error> {
+        let n = self.reserve()?;
+        let padded_len = self.padding.padded_len(payload.len(), &self.pad_key, n)?;
+        let buf = message.get_mut(..padded_len).ok_or(Error::Input)?;
+        buf[..payload.len()].copy_from_slice(payload);
+        self.padding.pad(buf, payload.len(), padded_len)?;
+        let len = self.send.encrypt_in_place_at(n, ad, message, padded_len)?;
+        observe(self.observer, Event::Sent { nonce: n });
+        Ok((n, len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    extern crate std;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_shared_sender() {
+        let (init, mut resp) = transport_pair();
+        let (_, write) = init.split();
+
+        const THREADS: u8 = 8;
+        const MESSAGES: usize = 500;
+        let sender = Arc::new(write.into_shared());
+        let (tx, rx) = channel();
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let sender = sender.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for i in 0..MESSAGES {
+                        let mut message = [0u8; 64];
+                        let payload = [t, i as u8];
+                        let (n, len) = sender.write_message(&payload, &mut message).unwrap();
+                        tx.send((n, payload, message[..len].to_vec())).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        let mut sent: Vec<_> = rx.iter().collect();
+        assert_eq!(sent.len(), THREADS as usize * MESSAGES);
+        sent.sort_by_key(|(n, _, _)| *n);
+        for (i, (n, payload, message)) in sent.iter().enumerate() {
+            // every nonce handed out exactly once, with no gaps
+            assert_eq!(*n, i as u64);
+            let mut out = [0u8; 64];
+            resp.set_receive_nonce(*n);
+            let len = resp.read_message(message, &mut out).unwrap();
+            assert_eq!(&out[..len], payload);
+        }
+        assert_eq!(sender.send_nonce(), sent.len() as u64);
+    }
+}
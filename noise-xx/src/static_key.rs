@@ -0,0 +1,143 @@
+use crate::{
+    x25519::{pub_key, x25519},
+    Error,
+};
+
+// The local static key pair. The handshake only needs the public key and
+// DH with the remote ephemeral key (es on the responder, se on the
+// initiator), so the secret can stay inside a secure element, TPM or HSM.
+pub trait StaticKey {
+    fn public_key(&self) -> [u8; 32];
+    // X25519 of the secret key and `remote`. The handshake rejects an
+    // all-zero result itself, an implementation may fail earlier.
+    fn dh(&mut self, remote: &[u8; 32]) -> Result<[u8; 32], Error>;
+}
+
+// Software key: the raw secret held in RAM.
+impl StaticKey for [u8; 32] {
+    fn public_key(&self) -> [u8; 32] {
+        pub_key(*self)
+    }
+    fn dh(&mut self, remote: &[u8; 32]) -> Result<[u8; 32], Error> {
+        x25519(*self, *remote)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+    extern crate std;
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+
+    // remote ephemeral key and where to send the result
+    type Request = ([u8; 32], Sender<Result<[u8; 32], Error>>);
+
+    // Holds the secret on another thread, like a signer reached over a bus
+    // or a socket, and only answers DH requests.
+    struct RemoteSigner {
+        public_key: [u8; 32],
+        requests: Sender<Request>,
+    }
+
+    impl RemoteSigner {
+        fn spawn(mut secret: [u8; 32]) -> Self {
+            let public_key = secret.public_key();
+            let (requests, rx) = channel::<Request>();
+            thread::spawn(move || {
+                for (remote, reply) in rx {
+                    let _ = reply.send(secret.dh(&remote));
+                }
+            });
+            Self {
+                public_key,
+                requests,
+            }
+        }
+    }
+
+    impl StaticKey for RemoteSigner {
+        fn public_key(&self) -> [u8; 32] {
+            self.public_key
+        }
+        fn dh(&mut self, remote: &[u8; 32]) -> Result<[u8; 32], Error> {
+            let (reply, rx) = channel();
+            self.requests
+                .send((*remote, reply))
+                .map_err(|_| Error::Dh)?;
+            rx.recv().map_err(|_| Error::Dh)?
+        }
+    }
+
+    #[test]
+    fn test_remote_signer() {
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+        let mut init = Handshake::init([0u8; 32], RemoteSigner::spawn([1u8; 32]), &[]);
+        let mut resp = Handshake::resp([2u8; 32], RemoteSigner::spawn([3u8; 32]), &[]);
+
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        let len = resp.write_message(&[], &mut buf_resp).unwrap();
+        init.read_message(&buf_resp[..len], &mut buf_init).unwrap();
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+
+        assert_eq!(resp.remote_key().unwrap(), [1u8; 32].public_key());
+        let mut init = init.upgrade().unwrap();
+        let mut resp = resp.upgrade().unwrap();
+        let len = init.write_message(b"signed", &mut buf_init).unwrap();
+        let len = resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        assert_eq!(&buf_resp[..len], b"signed");
+    }
+
+    #[test]
+    fn test_remote_signer_matches_software_key() {
+        let mut signer = RemoteSigner::spawn([1u8; 32]);
+        assert_eq!(signer.public_key(), [1u8; 32].public_key());
+        assert_eq!(
+            signer.dh(&[5u8; 32]).unwrap(),
+            [1u8; 32].dh(&[5u8; 32]).unwrap()
+        );
+        assert!(matches!(signer.dh(&[0u8; 32]), Err(Error::Dh)));
+    }
+
+    // A driver that reports success with an all-zero result, e.g. after a
+    // failed bus transfer.
+    struct Broken;
+
+    impl StaticKey for Broken {
+        fn public_key(&self) -> [u8; 32] {
+            [3u8; 32].public_key()
+        }
+        fn dh(&mut self, _: &[u8; 32]) -> Result<[u8; 32], Error> {
+            Ok([0u8; 32])
+        }
+    }
+
+    #[test]
+    fn test_all_zero_static_dh() {
+        let mut buf_init = [0u8; 100];
+        let mut buf_resp = [0u8; 100];
+        let mut init = Handshake::init([0u8; 32], Broken, &[]);
+        let mut resp = Handshake::resp([2u8; 32], Broken, &[]);
+        let len = init.write_message(&[], &mut buf_init).unwrap();
+        resp.read_message(&buf_init[..len], &mut buf_resp).unwrap();
+        // es on the responder
+        assert!(matches!(
+            resp.write_message(&[], &mut buf_resp),
+            Err(Error::Dh)
+        ));
+
+        // se on the initiator
+        let (_, mut resp) = handshake_pair();
+        let mut init = Handshake::init([0u8; 32], Broken, &[]);
+        exchange(&mut init, &mut resp);
+        exchange(&mut resp, &mut init);
+        assert!(matches!(
+            init.write_message(&[], &mut buf_init),
+            Err(Error::Dh)
+        ));
+    }
+}
@@ -15,6 +15,9 @@ pub(crate) struct SymmetricState {
     h: [u8; 32],
     cipher: CipherState,
     has_key: bool,
+    // raw copy of the cipher key for key logging
+    #[cfg(feature = "keylog")]
+    k: [u8; 32],
 }
 
 impl SymmetricState {
@@ -24,6 +27,25 @@ impl SymmetricState {
             h: PRE_H_NAME,
             cipher: CipherState::new([0u8; 32]),
             has_key: false,
+            #[cfg(feature = "keylog")]
+            k: [0u8; 32],
+        }
+    }
+    // InitializeSymmetric for a protocol name other than PROT_NAME
+    pub(crate) fn with_protocol_name(name: &[u8]) -> Self {
+        let mut h = [0u8; 32];
+        if name.len() <= 32 {
+            h[..name.len()].copy_from_slice(name);
+        } else {
+            h = blake2::Blake2s::digest(name).into();
+        }
+        Self {
+            ck: h,
+            h,
+            cipher: CipherState::new([0u8; 32]),
+            has_key: false,
+            #[cfg(feature = "keylog")]
+            k: [0u8; 32],
         }
     }
     pub(crate) fn mix_key(&mut self, input_material: &[u8]) {
@@ -33,10 +55,24 @@ impl SymmetricState {
         self.ck.copy_from_slice(&output[..32]);
         self.cipher = CipherState::new(output[32..].try_into().unwrap());
         self.has_key = true;
+        #[cfg(feature = "keylog")]
+        self.k.copy_from_slice(&output[32..]);
+    }
+    // for psk tokens
+    pub(crate) fn mix_key_and_hash(&mut self, input_material: &[u8]) {
+        let hkdf = Hkdf::<blake2::Blake2s>::new(Some(&self.ck), input_material);
+        let mut output = [0u8; 96];
+        hkdf.expand(&[], &mut output).unwrap();
+        self.ck.copy_from_slice(&output[..32]);
+        self.mix_hash(&output[32..64]);
+        self.cipher = CipherState::new(output[64..].try_into().unwrap());
+        self.has_key = true;
+        #[cfg(feature = "keylog")]
+        self.k.copy_from_slice(&output[64..]);
     }
     pub(crate) fn mix_hash(&mut self, data: &[u8]) {
         let mut hash = blake2::Blake2s::new();
-        hash.update(&self.h);
+        hash.update(self.h);
         hash.update(data);
         self.h = hash.finalize().into();
     }
@@ -58,6 +94,21 @@ impl SymmetricState {
         self.mix_hash(&message[..len]);
         Ok(len)
     }
+    // Like `encrypt_and_hash` with the payload already in message[..len].
+    pub(crate) fn encrypt_and_hash_in_place(
+        &mut self,
+        message: &mut [u8],
+        len: usize,
+    ) -> Result<usize, crate::Error> {
+        let len = if self.has_key {
+            self.cipher
+                .encrypt_in_place_with_ad(&self.h, message, len)?
+        } else {
+            len
+        };
+        self.mix_hash(&message[..len]);
+        Ok(len)
+    }
     pub(crate) fn decrypt_and_hash(
         &mut self,
         message: &[u8],
@@ -74,13 +125,42 @@ impl SymmetricState {
         Ok(len)
     }
 
+    pub(crate) fn handshake_hash(&self) -> [u8; 32] {
+        self.h
+    }
+    pub(crate) fn exporter_secret(&self) -> [u8; 32] {
+        let hkdf = Hkdf::<blake2::Blake2s>::new(Some(&self.ck), &self.h);
+        let mut output = [0u8; 32];
+        hkdf.expand(b"exporter", &mut output).unwrap();
+        output
+    }
+
+    // Padding keys of the initiator and of the responder.
+    pub(crate) fn padding_keys(&self) -> ([u8; 32], [u8; 32]) {
+        let hkdf = Hkdf::<blake2::Blake2s>::new(Some(&self.ck), &self.h);
+        let mut output = [0u8; 64];
+        hkdf.expand(b"padding", &mut output).unwrap();
+        (
+            output[..32].try_into().unwrap(),
+            output[32..].try_into().unwrap(),
+        )
+    }
+
     pub(crate) fn split(self) -> (CipherState, CipherState) {
+        let (k1, k2) = self.split_keys();
+        (CipherState::new(k1), CipherState::new(k2))
+    }
+    pub(crate) fn split_keys(&self) -> ([u8; 32], [u8; 32]) {
         let hkdf = Hkdf::<blake2::Blake2s>::new(Some(&self.ck), &[]);
         let mut output = [0u8; 64];
         hkdf.expand(&[], &mut output).unwrap();
         (
-            CipherState::new(output[..32].try_into().unwrap()),
-            CipherState::new(output[32..].try_into().unwrap()),
+            output[..32].try_into().unwrap(),
+            output[32..].try_into().unwrap(),
         )
     }
+    #[cfg(feature = "keylog")]
+    pub(crate) fn cipher_key(&self) -> [u8; 32] {
+        self.k
+    }
 }
@@ -0,0 +1,35 @@
+// Handshake fixtures shared by the test modules. The initiator's static key
+// is [1; 32] and the responder's [3; 32].
+use crate::{Handshake, StaticKey, Transport};
+
+pub(crate) fn handshake_pair() -> (Handshake, Handshake) {
+    (
+        Handshake::init([0u8; 32], [1u8; 32], &[]),
+        Handshake::resp([2u8; 32], [3u8; 32], &[]),
+    )
+}
+
+// Passes the next message, with an empty payload, from `from` to `to`.
+pub(crate) fn exchange<A: StaticKey, B: StaticKey>(from: &mut Handshake<A>, to: &mut Handshake<B>) {
+    let mut message = [0u8; 1000];
+    let mut payload = [0u8; 1000];
+    let len = from.write_message(&[], &mut message).unwrap();
+    to.read_message(&message[..len], &mut payload).unwrap();
+}
+
+// Runs all three messages, for handshakes that need their own keys or
+// settings.
+pub(crate) fn complete<A: StaticKey, B: StaticKey>(
+    init: &mut Handshake<A>,
+    resp: &mut Handshake<B>,
+) {
+    exchange(init, resp);
+    exchange(resp, init);
+    exchange(init, resp);
+}
+
+pub(crate) fn transport_pair() -> (Transport, Transport) {
+    let (mut init, mut resp) = handshake_pair();
+    complete(&mut init, &mut resp);
+    (init.upgrade().unwrap(), resp.upgrade().unwrap())
+}
@@ -1,7 +1,19 @@
-use crate::{CipherState, Error};
+use blake2::Digest;
+use hkdf::Hkdf;
+
+use crate::{
+    cipher_state::TAG_LEN,
+    observer::{observe, observe_result},
+    CipherState, Error, Event, Observer, Padding, PublicKey,
+};
 
 pub struct Transport {
     pub(crate) rs: [u8; 32],
+    pub(crate) ex: [u8; 32],
+    pub(crate) padding: Padding,
+    // our sending direction's, see `Padding::Random`
+    pub(crate) pad_key: [u8; 32],
+    pub(crate) observer: Option<&'static dyn Observer>,
     pub(crate) send: CipherState,
     pub(crate) recv: CipherState,
 }
@@ -9,16 +21,123 @@ pub struct Transport {
 pub struct NoiseRead {
     pub(crate) recv: CipherState,
     pub(crate) rs: [u8; 32],
+    pub(crate) ex: [u8; 32],
+    pub(crate) padding: Padding,
+    pub(crate) observer: Option<&'static dyn Observer>,
 }
 
 pub struct NoiseWrite {
     pub(crate) send: CipherState,
     pub(crate) rs: [u8; 32],
+    pub(crate) ex: [u8; 32],
+    pub(crate) padding: Padding,
+    pub(crate) pad_key: [u8; 32],
+    pub(crate) observer: Option<&'static dyn Observer>,
+}
+
+// HKDF-Expand(exporter_secret, len(label) || label || BLAKE2s(context))
+fn export_keying_material(
+    ex: &[u8; 32],
+    label: &[u8],
+    context: &[u8],
+    out: &mut [u8],
+) -> Result<(), Error> {
+    let label_len = [u8::try_from(label.len()).map_err(|_| Error::Input)?];
+    let context_hash = blake2::Blake2s::digest(context);
+    Hkdf::<blake2::Blake2s>::from_prk(ex)
+        .map_err(|_| Error::Input)?
+        .expand_multi_info(&[&label_len, label, &context_hash], out)
+        .map_err(|_| Error::Input)
+}
+
+// Encrypts the concatenation of `parts`, padded according to `padding`.
+fn write_padded(
+    send: &mut CipherState,
+    observer: Option<&dyn Observer>,
+    padding: Padding,
+    pad_key: &[u8; 32],
+    ad: &[u8],
+    parts: &[&[u8]],
+    message: &mut [u8],
+) -> Result<usize, Error> {
+    let len = parts.iter().map(|p| p.len()).sum();
+    let padded_len = padding.padded_len(len, pad_key, send.n)?;
+    let buf = message.get_mut(..padded_len).ok_or(Error::Input)?;
+    let mut offset = 0;
+    for part in parts {
+        buf[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+    padding.pad(buf, len, padded_len)?;
+    let nonce = send.n;
+    let len = send.encrypt_in_place_with_ad(ad, message, padded_len)?;
+    observe(observer, Event::Sent { nonce });
+    Ok(len)
+}
+
+fn write_batch(
+    send: &mut CipherState,
+    observer: Option<&dyn Observer>,
+    padding: Padding,
+    pad_key: &[u8; 32],
+    payloads: &[&[u8]],
+    out: &mut [u8],
+    lens: &mut [usize],
+) -> Result<usize, Error> {
+    if lens.len() < payloads.len() {
+        return Err(Error::Input);
+    }
+    // all or nothing: a message that does not fit would leave the earlier
+    // ones encrypted under nonces the caller never learns about
+    let mut total = 0usize;
+    for (i, payload) in payloads.iter().enumerate() {
+        let nonce = send.n.checked_add(i as u64).ok_or(Error::Input)?;
+        let padded_len = padding.padded_len(payload.len(), pad_key, nonce)?;
+        total = total
+            .checked_add(padded_len + TAG_LEN)
+            .ok_or(Error::Input)?;
+    }
+    if out.len() < total {
+        return Err(Error::Input);
+    }
+    let mut offset = 0;
+    for (payload, len) in payloads.iter().zip(lens.iter_mut()) {
+        *len = write_padded(
+            send,
+            observer,
+            padding,
+            pad_key,
+            &[],
+            &[payload],
+            &mut out[offset..],
+        )?;
+        offset += *len;
+    }
+    Ok(offset)
+}
+
+fn read_padded(
+    recv: &mut CipherState,
+    observer: Option<&dyn Observer>,
+    padding: Padding,
+    ad: &[u8],
+    message: &[u8],
+    payload: &mut [u8],
+) -> Result<usize, Error> {
+    let nonce = recv.n;
+    let result = recv
+        .decrypt_with_ad(ad, message, payload)
+        .and_then(|len| padding.unpad(payload, len));
+    if result.is_ok() {
+        observe(observer, Event::Received { nonce });
+    }
+    observe_result(observer, &result);
+    result
 }
 
 impl Transport {
-    pub fn remote_key(&self) -> [u8; 32] {
-        self.rs
+    pub fn remote_key(&self) -> PublicKey {
+        PublicKey(self.rs)
     }
     pub fn set_receive_nonce(&mut self, nonce: u64) {
         self.recv.set_nonce(nonce)
@@ -29,41 +148,207 @@ impl Transport {
     pub fn recv_nonce(&self) -> u64 {
         self.recv.n
     }
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+    pub fn rekey_send(&mut self) {
+        self.send.rekey();
+        observe(self.observer, Event::Rekey { send: true });
+    }
+    pub fn rekey_recv(&mut self) {
+        self.recv.rekey();
+        observe(self.observer, Event::Rekey { send: false });
+    }
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        export_keying_material(&self.ex, label, context, out)
+    }
     pub fn split(self) -> (NoiseRead, NoiseWrite) {
         (
             NoiseRead {
                 recv: self.recv,
                 rs: self.rs,
+                ex: self.ex,
+                padding: self.padding,
+                observer: self.observer,
             },
             NoiseWrite {
                 send: self.send,
                 rs: self.rs,
+                ex: self.ex,
+                padding: self.padding,
+                pad_key: self.pad_key,
+                observer: self.observer,
             },
         )
     }
     pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
-        self.recv.decrypt_with_ad(&[], message, payload)
+        self.read_message_with_ad(&[], message, payload)
     }
     pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
-        self.send.encrypt_with_ad(&[], payload, message)
+        self.write_message_with_ad(&[], payload, message)
+    }
+    pub fn read_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        read_padded(
+            &mut self.recv,
+            self.observer,
+            self.padding,
+            ad,
+            message,
+            payload,
+        )
+    }
+    pub fn write_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.write_vectored_with_ad(ad, &[payload], message)
+    }
+    // Encrypts the concatenation of `parts` as one message, e.g. a header
+    // and a body, without first joining them in another buffer.
+    pub fn write_vectored(&mut self, parts: &[&[u8]], message: &mut [u8]) -> Result<usize, Error> {
+        self.write_vectored_with_ad(&[], parts, message)
+    }
+    pub fn write_vectored_with_ad(
+        &mut self,
+        ad: &[u8],
+        parts: &[&[u8]],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        write_padded(
+            &mut self.send,
+            self.observer,
+            self.padding,
+            &self.pad_key,
+            ad,
+            parts,
+            message,
+        )
+    }
+    // Encrypts each payload as its own message, back to back in `out`.
+    // lens[i] is set to the length of message i, the total is returned.
+    // Fails without encrypting anything if `out` cannot hold them all.
+    pub fn write_batch(
+        &mut self,
+        payloads: &[&[u8]],
+        out: &mut [u8],
+        lens: &mut [usize],
+    ) -> Result<usize, Error> {
+        write_batch(
+            &mut self.send,
+            self.observer,
+            self.padding,
+            &self.pad_key,
+            payloads,
+            out,
+            lens,
+        )
     }
 }
 
 impl NoiseRead {
-    pub fn remote_key(&self) -> [u8; 32] {
-        self.rs
+    pub fn remote_key(&self) -> PublicKey {
+        PublicKey(self.rs)
+    }
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        export_keying_material(&self.ex, label, context, out)
     }
 
     pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
-        self.recv.decrypt_with_ad(&[], message, payload)
+        self.read_message_with_ad(&[], message, payload)
+    }
+    pub fn read_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        read_padded(
+            &mut self.recv,
+            self.observer,
+            self.padding,
+            ad,
+            message,
+            payload,
+        )
     }
 }
 
 impl NoiseWrite {
-    pub fn remote_key(&self) -> [u8; 32] {
-        self.rs
+    pub fn remote_key(&self) -> PublicKey {
+        PublicKey(self.rs)
+    }
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        export_keying_material(&self.ex, label, context, out)
     }
     pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
-        self.send.encrypt_with_ad(&[], payload, message)
+        self.write_message_with_ad(&[], payload, message)
+    }
+    pub fn write_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.write_vectored_with_ad(ad, &[payload], message)
+    }
+    pub fn write_vectored(&mut self, parts: &[&[u8]], message: &mut [u8]) -> Result<usize, Error> {
+        self.write_vectored_with_ad(&[], parts, message)
+    }
+    pub fn write_vectored_with_ad(
+        &mut self,
+        ad: &[u8],
+        parts: &[&[u8]],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        write_padded(
+            &mut self.send,
+            self.observer,
+            self.padding,
+            &self.pad_key,
+            ad,
+            parts,
+            message,
+        )
+    }
+    pub fn write_batch(
+        &mut self,
+        payloads: &[&[u8]],
+        out: &mut [u8],
+        lens: &mut [usize],
+    ) -> Result<usize, Error> {
+        write_batch(
+            &mut self.send,
+            self.observer,
+            self.padding,
+            &self.pad_key,
+            payloads,
+            out,
+            lens,
+        )
     }
 }
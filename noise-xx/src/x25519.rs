@@ -7,7 +7,11 @@ pub(crate) fn pub_key(k: [u8; 32]) -> [u8; 32] {
 }
 
 pub(crate) fn x25519(k: [u8; 32], u: [u8; 32]) -> Result<[u8; 32], Error> {
-    let out = x25519_dalek::x25519(k, u);
+    check_dh(x25519_dalek::x25519(k, u))
+}
+
+// Rejects the all-zero result of DH with a low order point.
+pub(crate) fn check_dh(out: [u8; 32]) -> Result<[u8; 32], Error> {
     if out.iter().any(|b| *b != 0u8) {
         Ok(out)
     } else {